@@ -0,0 +1,193 @@
+use crate::{Error, Result};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::Deserialize;
+use std::{collections::HashSet, fs, path::PathBuf};
+use util::{
+    config::{is_address, Loader, Problems},
+    logging::Logging,
+};
+
+/// Client configuration, merged from the file, the environment and flags.
+///
+/// ```toml
+/// host = "proxy.example:4000"
+/// cert = "/etc/client/server.pem"
+/// token = { file = "/etc/client/token" }
+/// # or instead of the token, a private key whose public key is registered
+/// # key = "/etc/client/client.pem"
+/// # or a JWT of the identity provider the server trusts
+/// # jwt = { file = "/run/client/jwt" }
+///
+/// [[forward]]
+/// bind = "localhost:5432"
+/// hostname = "node7"
+/// port = 5432
+/// ```
+pub struct Config {
+    /// Address of the proxy server.
+    pub host: String,
+    /// PKCS#1 PEM public key file of the server.
+    pub cert: String,
+    pub token: Option<String>,
+    /// PKCS#1 PEM private key file, authenticates instead of the token.
+    pub key: Option<String>,
+    /// JWT of the identity provider, authenticates instead of the token.
+    pub jwt: Option<String>,
+    /// Forwards established at startup and after reconnects.
+    pub forwards: Vec<ForwardConfig>,
+    pub logging: Logging,
+}
+
+/// Where the authentication token or JWT is read from.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TokenSource {
+    Inline(String),
+    File { file: PathBuf },
+    Env { env: String },
+}
+
+/// A local listener forwarding to a port of a node.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// Local address, or socket path for unix forwards.
+    pub bind: String,
+    pub hostname: String,
+    pub port: u32,
+    #[serde(default, rename = "type")]
+    pub kind: ForwardType,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardType {
+    #[default]
+    Tcp,
+    Udp,
+    /// Listens on a unix socket, the node port is TCP.
+    Unix,
+}
+
+impl Config {
+    /// The file at `path` and the environment. Command line flags are added
+    /// on top by the caller.
+    pub fn layers(path: Option<&str>) -> Loader {
+        Logging::layers(Loader::new())
+            .file(path)
+            .env("host", "HOST")
+            .env("cert", "CERT")
+            .env("token", "TOKEN")
+            .env("key", "PRIVATE_KEY")
+            .env("jwt", "JWT")
+    }
+
+    /// Reads the configuration and the token, requiring a token or key if
+    /// `authenticate` is set.
+    pub fn load(mut loader: Loader, authenticate: bool) -> std::result::Result<Self, Problems> {
+        let host = loader.require::<String>("host");
+        let cert = loader.require::<String>("cert");
+        let token = loader.get::<TokenSource>("token");
+        let key = loader.get::<String>("key");
+        let jwt = loader.get::<TokenSource>("jwt");
+        let forwards = loader
+            .get::<Vec<ForwardConfig>>("forward")
+            .unwrap_or_default();
+        let logging = Logging::load(&mut loader);
+
+        if host.as_deref().is_some_and(|host| !is_address(host)) {
+            loader.problem("host", "is not an address of the form host:port");
+        }
+
+        if let Some(cert) = &cert {
+            if let Err(error) = RsaPublicKey::read_pkcs1_pem_file(cert) {
+                loader.problem(
+                    "cert",
+                    format!("cannot read PKCS#1 PEM public key: {error}"),
+                );
+            }
+        }
+
+        if let Some(key) = &key {
+            if let Err(error) = RsaPrivateKey::read_pkcs1_pem_file(key) {
+                loader.problem(
+                    "key",
+                    format!("cannot read PKCS#1 PEM private key: {error}"),
+                );
+            }
+        } else if authenticate && token.is_none() && jwt.is_none() {
+            loader.problem("token", "is required unless a key or JWT is given");
+        }
+
+        let token = token.and_then(|token| match token.read() {
+            Ok(token) if util::auth::split_token(&token).is_none() => {
+                loader.problem("token", "is not of the form <id>:<secret>");
+                None
+            }
+            Ok(token) => Some(token),
+            Err(error) => {
+                loader.problem("token", error.to_string());
+                None
+            }
+        });
+
+        let jwt = jwt.and_then(|jwt| match jwt.read() {
+            Ok(jwt) => Some(jwt),
+            Err(error) => {
+                loader.problem("jwt", error.to_string());
+                None
+            }
+        });
+
+        let mut binds = HashSet::new();
+        for forward in &forwards {
+            if !binds.insert(&forward.bind) {
+                loader.problem(
+                    "forward",
+                    format!("{} is bound more than once", forward.bind),
+                );
+            }
+            if forward.kind != ForwardType::Unix && !is_address(&forward.bind) {
+                loader.problem(
+                    "forward",
+                    format!("{} is not an address of the form host:port", forward.bind),
+                );
+            }
+        }
+
+        let problems = loader.finish();
+        match (host, cert, token, logging) {
+            (Some(host), Some(cert), token, Some(logging)) if problems.is_empty() => Ok(Self {
+                host,
+                cert,
+                token,
+                key,
+                jwt,
+                forwards,
+                logging,
+            }),
+            _ => Err(problems),
+        }
+    }
+}
+
+impl TokenSource {
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Self::Inline(token) => Ok(token.clone()),
+            Self::File { file } => fs::read_to_string(file)
+                .map(|token| token.trim_end().to_owned())
+                .map_err(|error| {
+                    Error::Config(format!(
+                        "cannot read token file {}: {error}",
+                        file.display()
+                    ))
+                }),
+            Self::Env { env } => std::env::var(env)
+                .map_err(|_| Error::Config(format!("cannot find environment variable {env}"))),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::{fmt, io};
+
+/// Errors of the client library.
+#[derive(Debug)]
+pub enum Error {
+    /// Server public key cannot be read.
+    PublicKey,
+    /// Client private key cannot be read or used.
+    PrivateKey,
+    /// Connection to the server has failed or closed.
+    Connection,
+    /// Local socket error.
+    Io(io::Error),
+    /// The server refused the command.
+    Server(String),
+    /// The token is malformed or the server refused it.
+    Authentication(String),
+    /// The server refused the command since a quota is used up.
+    QuotaExceeded(String),
+    /// The server sent a response that does not match the command.
+    UnexpectedResponse,
+    /// Invalid or unreadable configuration.
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PublicKey => write!(f, "cannot read server public key"),
+            Self::PrivateKey => write!(f, "cannot use client private key"),
+            Self::Connection => write!(f, "connection to the server failed"),
+            Self::Io(error) => write!(f, "{error}"),
+            Self::Server(message) => write!(f, "server error: {message}"),
+            Self::Authentication(message) => write!(f, "authentication failed: {message}"),
+            Self::QuotaExceeded(message) => write!(f, "quota exceeded: {message}"),
+            Self::UnexpectedResponse => write!(f, "unexpected response from the server"),
+            Self::Config(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
@@ -0,0 +1,302 @@
+use crate::Client;
+use std::{
+    fs,
+    future::Future,
+    io,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+use util::{copy_bidirectional, Protocol};
+
+/// Handle of a running port forwarding. Resolves when the forwarding ends and
+/// closes it, including its live streams, when dropped.
+pub struct Forward {
+    local: String,
+    hostname: String,
+    port: u32,
+    started: Instant,
+    stats: Arc<Stats>,
+    task: JoinHandle<()>,
+}
+
+/// Traffic counters shared with the streams of a forwarding.
+#[derive(Default)]
+pub(crate) struct Stats {
+    connections: AtomicU64,
+    active: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// Local listeners that streams of a forwarding are accepted from.
+pub(crate) trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + 'static;
+
+    fn accept_stream(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+impl Forward {
+    /// Spawns a forwarding task that reports to the stats of the handle.
+    pub(crate) fn spawn<F, Fut>(local: String, hostname: String, port: u32, run: F) -> Self
+    where
+        F: FnOnce(Arc<Stats>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let stats = Arc::new(Stats::default());
+        let span = info_span!("forward", local, hostname, port);
+        let run = run(Arc::clone(&stats));
+
+        Self {
+            local,
+            hostname,
+            port,
+            started: Instant::now(),
+            task: tokio::spawn(
+                async move {
+                    info!("forward started");
+                    run.await;
+                    info!("forward closed");
+                }
+                .instrument(span),
+            ),
+            stats,
+        }
+    }
+
+    /// Spawns accept loop of a local listener. Each accepted stream is
+    /// forwarded through a fresh stream to `port` of the node `hostname`.
+    pub(crate) fn listen<L: Listener>(
+        client: Arc<Client>,
+        listener: L,
+        local: String,
+        hostname: String,
+        port: u32,
+    ) -> Self {
+        Self::spawn(local, hostname.clone(), port, |stats| async move {
+            // aborts the live streams when the listener is closed
+            let mut streams = JoinSet::new();
+
+            loop {
+                tokio::select! {
+                    accepted = listener.accept_stream() => {
+                        let Ok(stream) = accepted else {
+                            break;
+                        };
+
+                        let client = Arc::clone(&client);
+                        let stats = Arc::clone(&stats);
+                        let hostname = hostname.clone();
+
+                        // a fresh stream for each local connection
+                        streams.spawn(
+                            async move {
+                                match client.open(&hostname, port, Protocol::Tcp).await {
+                                    Ok(forward) => {
+                                        debug!("stream opened");
+                                        stats.pipe(forward, tokio::io::split(stream)).await;
+                                    }
+                                    Err(error) => warn!(%error, "cannot open stream"),
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                    Some(_) = streams.join_next() => {}
+                }
+            }
+        })
+    }
+
+    /// Local address or socket path the forwarding listens on, or the shared
+    /// service for share streams.
+    pub fn local(&self) -> &str {
+        &self.local
+    }
+
+    /// Remote node of the forwarding.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Remote port of the forwarding.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Number of streams opened so far.
+    pub fn connections(&self) -> u64 {
+        self.stats.connections.load(Ordering::Relaxed)
+    }
+
+    /// Number of live streams.
+    pub fn active_connections(&self) -> u64 {
+        self.stats.active.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent from the local side to the node.
+    pub fn bytes_sent(&self) -> u64 {
+        self.stats.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from the node.
+    pub fn bytes_received(&self) -> u64 {
+        self.stats.received.load(Ordering::Relaxed)
+    }
+
+    /// Whether the forwarding has ended.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Future for Forward {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.task).poll(cx).map(|_| ())
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_stream(&self) -> io::Result<TcpStream> {
+        Ok(self.accept().await?.0)
+    }
+}
+
+/// Listener on a Unix socket that removes its socket file when dropped, so
+/// the path can be bound again after reconnecting.
+pub(crate) struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Binds `path`, removing the socket file an earlier run left behind.
+    /// Sockets something still listens on are kept.
+    pub(crate) fn bind(path: &str) -> io::Result<Self> {
+        let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket())
+            && std::os::unix::net::UnixStream::connect(path).is_err();
+        if stale {
+            fs::remove_file(path)?;
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+impl Listener for UnixSocket {
+    type Stream = UnixStream;
+
+    async fn accept_stream(&self) -> io::Result<UnixStream> {
+        Ok(self.listener.accept().await?.0)
+    }
+}
+
+impl Stats {
+    pub(crate) fn start(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn end(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_sent(&self, n: usize) {
+        self.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_received(&self, n: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Copies data between a server stream and a local stream while counting
+    /// the transferred bytes.
+    pub(crate) async fn pipe<R, W, LR, LW>(
+        self: &Arc<Self>,
+        (r, w): (R, W),
+        (local_r, local_w): (LR, LW),
+    ) where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+        LR: AsyncRead + Unpin,
+        LW: AsyncWrite + Unpin,
+    {
+        self.start();
+
+        let r = Counted {
+            inner: r,
+            counter: Arc::clone(self),
+            sent: false,
+        };
+        let local_r = Counted {
+            inner: local_r,
+            counter: Arc::clone(self),
+            sent: true,
+        };
+        copy_bidirectional((r, w), (local_r, local_w)).await;
+
+        self.end();
+    }
+}
+
+/// Reader that adds the read bytes to the stats of its forwarding.
+struct Counted<R> {
+    inner: R,
+    counter: Arc<Stats>,
+    sent: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+
+        if self.sent {
+            self.counter.add_sent(read);
+        } else {
+            self.counter.add_received(read);
+        }
+
+        poll
+    }
+}
@@ -1,192 +1,503 @@
+//! Client for TCP port forwarding. Opens a control [`Session`] to the proxy
+//! server, receives ports of nodes and shares local ports when connected as a
+//! node.
+
+/// Configuration file of the client.
+pub mod config;
+
+/// Errors of the client library.
+pub mod error;
+
+/// Handles of running port forwardings.
+pub mod forward;
+
+/// Interactive command line frontend.
+pub mod repl;
+
+/// Control connection to the proxy server.
+pub mod session;
+
+/// Datagram relaying over forwarding streams.
+mod udp;
+
+pub use config::{Config, ForwardConfig, ForwardType};
+pub use error::{Error, Result};
+pub use forward::Forward;
+pub use session::Session;
+
+use forward::UnixSocket;
+use session::Control;
+
 use ptls::Ptls;
 use rand::thread_rng;
-use rsa::{pkcs1::DecodeRsaPublicKey, RsaPrivateKey, RsaPublicKey};
-use std::sync::Arc;
+use rsa::{
+    pkcs1::{
+        DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPrivateKey, EncodeRsaPublicKey,
+        LineEnding,
+    },
+    RsaPrivateKey, RsaPublicKey,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
 use tokio::{
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+    },
 };
+use tracing::{debug, warn};
 use util::*;
 
-pub struct Client {}
+/// Reconnect delay of [`Client::up`], doubled up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connection parameters of a proxy server.
+#[derive(Clone)]
+pub struct Client {
+    addr: String,
+    server_public: RsaPublicKey,
+    credential: Credential,
+    /// Control connection of the latest session, which issues the tickets
+    /// of the data connections.
+    control: Arc<Mutex<Weak<Control>>>,
+}
+
+/// What the client authenticates with.
+#[derive(Clone)]
+enum Credential {
+    Token {
+        /// Replaced when the token is rotated.
+        token: Arc<Mutex<String>>,
+        /// Proof key derived from the token for the last Argon2 setting, so
+        /// it is not derived again on every connection.
+        derived: Arc<Mutex<Option<DerivedKey>>>,
+    },
+    /// Private key of a public key registered on the server.
+    PrivateKey(Box<RsaPrivateKey>),
+    /// JWT of the identity provider the server trusts.
+    Jwt(Secret<String>),
+}
+
+/// Argon2 setting and the proof key derived with it.
+type DerivedKey = (String, Secret<Vec<u8>>);
 
 impl Client {
-    pub async fn connect(addr: &str, public_key: &str, token: &str) {
-        macro_rules! print {
-            ($text:expr) => {{
-                let mut stdout = io::stdout();
-                stdout.write_all($text.as_bytes()).await.ok();
-                stdout.flush().await.ok();
-            }};
-        }
+    pub fn new(addr: &str, public_key_file: &str, token: &str) -> Result<Self> {
+        Ok(Self {
+            addr: addr.to_owned(),
+            server_public: RsaPublicKey::read_pkcs1_pem_file(public_key_file)
+                .map_err(|_| Error::PublicKey)?,
+            credential: Credential::Token {
+                token: Arc::new(Mutex::new(token.to_owned())),
+                derived: Arc::default(),
+            },
+            control: Arc::default(),
+        })
+    }
+
+    /// Authenticates with the PKCS#1 PEM private key in `private_key_file`,
+    /// whose public key is registered on the server, instead of a token.
+    pub fn with_key(addr: &str, public_key_file: &str, private_key_file: &str) -> Result<Self> {
+        Ok(Self {
+            addr: addr.to_owned(),
+            server_public: RsaPublicKey::read_pkcs1_pem_file(public_key_file)
+                .map_err(|_| Error::PublicKey)?,
+            credential: Credential::PrivateKey(Box::new(
+                RsaPrivateKey::read_pkcs1_pem_file(private_key_file)
+                    .map_err(|_| Error::PrivateKey)?,
+            )),
+            control: Arc::default(),
+        })
+    }
 
-        let client = TcpStream::connect(addr)
-            .await
-            .expect(" connect to the remote add");
+    /// Authenticates with a JWT of the identity provider the server trusts
+    /// instead of a token.
+    pub fn with_jwt(addr: &str, public_key_file: &str, jwt: &str) -> Result<Self> {
+        Ok(Self {
+            addr: addr.to_owned(),
+            server_public: RsaPublicKey::read_pkcs1_pem_file(public_key_file)
+                .map_err(|_| Error::PublicKey)?,
+            credential: Credential::Jwt(Secret(jwt.to_owned())),
+            control: Arc::default(),
+        })
+    }
 
-        let client_private = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+    /// Exchanges the enrollment `code` for the credential of a new client
+    /// and writes it to `path`, which must not exist. The credential is a
+    /// generated private key whose public key the server registers if `key`
+    /// is set, otherwise a token. Returns the hostname of the client.
+    pub async fn enroll(
+        addr: &str,
+        public_key_file: &str,
+        code: &str,
+        path: &Path,
+        key: bool,
+    ) -> Result<String> {
         let server_public =
-            RsaPublicKey::read_pkcs1_pem_file(public_key).expect("Cannot read server public key");
+            RsaPublicKey::read_pkcs1_pem_file(public_key_file).map_err(|_| Error::PublicKey)?;
+        let private_key = key
+            .then(|| RsaPrivateKey::new(&mut thread_rng(), 2048))
+            .transpose()
+            .map_err(|_| Error::PrivateKey)?;
+        let public_key = private_key
+            .as_ref()
+            .map(|private_key| RsaPublicKey::from(private_key).to_pkcs1_pem(LineEnding::LF))
+            .transpose()
+            .map_err(|_| Error::PrivateKey)?;
+
+        // created first, so a credential is never issued that cannot be kept
+        let mut file = create_secret_file(path)?;
+        let enrolled = async {
+            let client_ptls = handshake(addr, &server_public).await?;
+            let enroll = Cmd::Enroll {
+                code: Secret(code.to_owned()),
+                public_key,
+            };
+            match request(&client_ptls, &enroll).await? {
+                Cmd::Enrolled { hostname, token } => Ok((hostname, token)),
+                _ => Err(Error::UnexpectedResponse),
+            }
+        }
+        .await;
+
+        let (hostname, token) = match enrolled {
+            Ok(enrolled) => enrolled,
+            Err(error) => {
+                drop(file);
+                fs::remove_file(path).ok();
+                return Err(error);
+            }
+        };
+        let contents = match (&private_key, &token) {
+            (Some(private_key), _) => private_key
+                .to_pkcs1_pem(LineEnding::LF)
+                .map_err(|_| Error::PrivateKey)?
+                .to_string(),
+            (None, Some(token)) => format!("{}\n", token.0),
+            (None, None) => return Err(Error::UnexpectedResponse),
+        };
+        file.write_all(contents.as_bytes())?;
+
+        Ok(hostname)
+    }
 
-        let mut client_ptls = Ptls::new(client.into_split(), client_private);
-        client_ptls.set_public_key(server_public.clone());
-        client_ptls
-            .send_public_key()
-            .await
-            .expect("Cannot send public key to the server");
+    /// Opens a control session. Share requests of the server are answered
+    /// while the session is alive.
+    pub async fn connect(&self) -> Result<Session> {
+        let client_ptls = self.authenticate().await?;
 
-        let client_ptls = Arc::new(client_ptls);
+        Ok(Session::new(self.clone(), client_ptls))
+    }
+
+    /// Opens a single stream to `port` of the node `hostname`. Datagrams of
+    /// UDP ports are length prefixed on the stream.
+    pub async fn open(
+        &self,
+        hostname: &str,
+        port: u32,
+        protocol: Protocol,
+    ) -> Result<(OwnedReadHalf, OwnedWriteHalf)> {
+        let purpose = TicketPurpose::GetPort {
+            hostname: hostname.to_owned(),
+            port,
+            protocol,
+        };
+        let client_ptls = self.data_connection(purpose).await?;
+
+        Ok(client_ptls.into_inner())
+    }
 
-        client_ptls
-            .send(
-                &bincode::serialize(&Cmd::Authenticate {
-                    token: token.as_bytes().to_vec(),
+    /// Listens on `local_addr` and forwards each accepted connection to
+    /// `port` of the node `hostname` through a fresh stream.
+    pub async fn get_port<A: ToSocketAddrs>(
+        &self,
+        hostname: &str,
+        port: u32,
+        local_addr: A,
+    ) -> Result<Forward> {
+        let listener = TcpListener::bind(local_addr).await?;
+        let local = listener.local_addr()?.to_string();
+
+        Ok(Forward::listen(
+            Arc::new(self.clone()),
+            listener,
+            local,
+            hostname.to_owned(),
+            port,
+        ))
+    }
+
+    /// Starts a forwarding described in the configuration file.
+    pub async fn forward(&self, config: &ForwardConfig) -> Result<Forward> {
+        let client = Arc::new(self.clone());
+        let ForwardConfig {
+            bind,
+            hostname,
+            port,
+            kind,
+        } = config.clone();
+
+        Ok(match kind {
+            ForwardType::Tcp => return self.get_port(&hostname, port, &bind).await,
+            ForwardType::Unix => {
+                let listener = UnixSocket::bind(&bind)?;
+
+                Forward::listen(client, listener, bind, hostname, port)
+            }
+            ForwardType::Udp => {
+                let socket = UdpSocket::bind(&bind).await?;
+
+                Forward::spawn(bind, hostname.clone(), port, |stats| {
+                    udp::listen(client, socket, hostname, port, stats)
                 })
-                .unwrap(),
-            )
-            .await
-            .expect("Authentication failed");
-
-        tokio::spawn({
-            let client_ptls = Arc::clone(&client_ptls);
-            let server_public = server_public.clone();
-            let token = token.to_owned();
-            let addr = addr.to_owned();
-
-            async move {
-                while let Ok(received) = client_ptls.receive().await {
-                    let cmd: Cmd = if let Ok(cmd) = bincode::deserialize(&received) {
-                        cmd
-                    } else {
-                        continue;
-                    };
-
-                    match cmd {
-                        Cmd::SharePort { port, id } => {
-                            let forward_client = TcpStream::connect(&addr).await.unwrap();
-                            let client_private =
-                                RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
-
-                            let mut client_ptls =
-                                Ptls::new(forward_client.into_split(), client_private);
-                            client_ptls.set_public_key(server_public.clone());
-                            client_ptls.send_public_key().await.unwrap();
-
-                            client_ptls
-                                .send(
-                                    &bincode::serialize(&Cmd::Authenticate {
-                                        token: token.as_bytes().to_vec(),
-                                    })
-                                    .unwrap(),
-                                )
-                                .await
-                                .unwrap();
-
-                            client_ptls
-                                .send(&bincode::serialize(&Cmd::SharePort { port, id }).unwrap())
-                                .await
-                                .unwrap();
-
-                            let (r, w) = client_ptls.into_inner();
-                            let (target_r, target_w) =
-                                TcpStream::connect(&format!("localhost:{port}"))
-                                    .await
-                                    .unwrap()
-                                    .into_split();
-
-                            tokio::spawn(copy_bidirectional((r, w), (target_r, target_w)));
-                        }
-                        Cmd::Noop => {}
-                        _ => {}
-                    }
-                }
             }
-        });
+        })
+    }
 
-        let stdin = io::stdin();
+    /// Answers the share request `id` by forwarding local `port` to the server.
+    pub async fn share(&self, port: u32, id: u64, protocol: Protocol) -> Result<Forward> {
+        let purpose = TicketPurpose::SharePort { port, id, protocol };
+        let forward = self.data_connection(purpose).await?.into_inner();
+        let local = format!("localhost:{port}");
+        let hostname = String::from("localhost");
 
-        let mut br = BufReader::new(stdin);
+        Ok(match protocol {
+            Protocol::Tcp => {
+                let target = TcpStream::connect(&local).await?;
 
-        loop {
-            print!("proxy > ");
-            let mut line = String::new();
-            if br.read_line(&mut line).await.is_err() {
-                continue;
+                Forward::spawn(local, hostname, port, |stats| async move {
+                    stats.pipe(forward, target.into_split()).await
+                })
+            }
+            Protocol::Udp => {
+                let socket = udp::connect_local(port).await?;
+
+                Forward::spawn(local, hostname, port, |stats| {
+                    udp::share(forward, socket, stats)
+                })
             }
+        })
+    }
+
+    /// Keeps a control session and `forwards` up. The forwards are
+    /// re-established whenever the session is reconnected.
+    pub async fn up(&self, forwards: &[ForwardConfig]) -> ! {
+        let mut backoff = MIN_BACKOFF;
 
-            line.pop();
-            let line = line.split(" ").collect::<Vec<&str>>();
-
-            match line[0] {
-                "get" => {
-                    let hostname = line[1].to_owned();
-                    let port: Option<u32> = line[2].parse().ok();
-                    let local_port: Option<u32> = line[3].parse().ok();
-
-                    if let (Some(port), Some(local_port)) = (port, local_port) {
-                        let server = TcpListener::bind(&format!("localhost:{local_port}"))
-                            .await
-                            .unwrap();
-
-                        let addr = addr.to_owned();
-                        let server_public = server_public.to_owned();
-                        let token = token.to_owned();
-
-                        tokio::spawn(async move {
-                            let forward_client = TcpStream::connect(addr).await.unwrap();
-                            let client_private =
-                                RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
-
-                            let mut client_ptls =
-                                Ptls::new(forward_client.into_split(), client_private);
-                            client_ptls.set_public_key(server_public.clone());
-                            client_ptls.send_public_key().await.unwrap();
-
-                            client_ptls
-                                .send(
-                                    &bincode::serialize(&Cmd::Authenticate {
-                                        token: token.as_bytes().to_vec(),
-                                    })
-                                    .unwrap(),
-                                )
-                                .await
-                                .unwrap();
-
-                            client_ptls
-                                .send(
-                                    &bincode::serialize(&Cmd::GetPort { hostname, port }).unwrap(),
-                                )
-                                .await
-                                .unwrap();
-
-                            let (r, w) = client_ptls.into_inner();
-                            if let Ok((stream, _)) = server.accept().await {
-                                let (target_r, target_w) = stream.into_split();
-                                copy_bidirectional((r, w), (target_r, target_w)).await;
-                            };
-                        });
-                        print!("requested port\n");
-                    } else {
-                        print!("cannot parse port\n");
+        loop {
+            match self.connect().await {
+                Ok(session) => {
+                    backoff = MIN_BACKOFF;
+
+                    let mut handles = Vec::new();
+                    for forward in forwards {
+                        match self.forward(forward).await {
+                            Ok(handle) => handles.push(handle),
+                            Err(error) => warn!(bind = forward.bind, %error, "cannot forward"),
+                        }
                     }
+
+                    // the forwards are closed with their handles
+                    session.closed().await;
+                    warn!("connection closed, reconnecting");
                 }
-                "add_usr" => {
-                    client_ptls
-                        .send(
-                            &bincode::serialize(&Cmd::AddClient {
-                                username: line[1].to_owned(),
-                                token: line[2].to_owned(),
-                                permission_level: PermissionLevel::Standart,
-                            })
-                            .unwrap(),
-                        )
-                        .await
-                        .unwrap();
-                }
-                _ => {
-                    print!("unknown command\n");
+                Err(error) => warn!(%error, ?backoff, "cannot connect"),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Pipes stdin and stdout through `port` of the node `hostname` until the
+    /// stream closes.
+    pub async fn stdio(&self, hostname: &str, port: u32) -> Result<()> {
+        let forward = self.open(hostname, port, Protocol::Tcp).await?;
+
+        copy_bidirectional(forward, (io::stdin(), io::stdout())).await;
+        Ok(())
+    }
+
+    /// Authenticates later connections with `token`, after it was rotated.
+    pub(crate) fn replace_token(&self, token: &str) {
+        if let Credential::Token { token: current, .. } = &self.credential {
+            *current.lock().unwrap() = token.to_owned();
+        }
+    }
+
+    /// Makes the data connections of the client use the tickets of
+    /// `control`.
+    pub(crate) fn set_control(&self, control: &Arc<Control>) {
+        *self.control.lock().unwrap() = Arc::downgrade(control);
+    }
+
+    /// Opens a data connection and runs the command of `purpose` on it. It
+    /// is admitted with a ticket of the control connection if there is one,
+    /// so the token or key is only proven once per session.
+    async fn data_connection(
+        &self,
+        purpose: TicketPurpose,
+    ) -> Result<Ptls<OwnedReadHalf, OwnedWriteHalf>> {
+        let control = self.control.lock().unwrap().upgrade();
+        let ticket = match control {
+            Some(control) => match control
+                .request(Cmd::IssueTicket {
+                    purpose: purpose.clone(),
+                })
+                .await
+            {
+                Ok(Cmd::Ticket { ticket }) => Some(ticket),
+                Ok(_) => None,
+                Err(error) => {
+                    debug!(%error, "cannot get a ticket, authenticating");
+                    None
                 }
+            },
+            None => None,
+        };
+
+        let client_ptls = match ticket {
+            Some(ticket) => {
+                let client_ptls = self.handshake().await?;
+                request(&client_ptls, &Cmd::Redeem { ticket }).await?;
+                client_ptls
+            }
+            None => {
+                let client_ptls = self.authenticate().await?;
+                request(&client_ptls, &purpose.command()).await?;
+                client_ptls
+            }
+        };
+
+        Ok(client_ptls)
+    }
+
+    /// Connects to the server with a fresh ptls key.
+    async fn handshake(&self) -> Result<Ptls<OwnedReadHalf, OwnedWriteHalf>> {
+        handshake(&self.addr, &self.server_public).await
+    }
+
+    /// Connects to the server and proves the token or the private key in
+    /// answer to a challenge of the server, see [`util::auth`], or presents
+    /// the JWT.
+    async fn authenticate(&self) -> Result<Ptls<OwnedReadHalf, OwnedWriteHalf>> {
+        let client_ptls = self.handshake().await?;
+
+        if let Credential::Jwt(jwt) = &self.credential {
+            request(&client_ptls, &Cmd::Bearer { jwt: jwt.clone() }).await?;
+            return Ok(client_ptls);
+        }
+
+        let proof = match &self.credential {
+            Credential::Token { token, derived } => {
+                let token = token.lock().unwrap().clone();
+                let (token_id, secret) = auth::split_token(&token)
+                    .ok_or_else(|| Error::Authentication(String::from("malformed token")))?;
+                let hello = Cmd::Hello {
+                    token_id: token_id.to_owned(),
+                };
+                let Cmd::Challenge { nonce, setting } = request(&client_ptls, &hello).await? else {
+                    return Err(Error::UnexpectedResponse);
+                };
+
+                let key = derive(derived, secret, &setting)?;
+                auth::proof(&key, &nonce, token_id)
             }
+            Credential::PrivateKey(private_key) => {
+                let public_key = RsaPublicKey::from(&**private_key)
+                    .to_pkcs1_pem(LineEnding::LF)
+                    .map_err(|_| Error::PrivateKey)?;
+                let hello = Cmd::KeyHello { public_key };
+                let Cmd::Challenge { nonce, .. } = request(&client_ptls, &hello).await? else {
+                    return Err(Error::UnexpectedResponse);
+                };
+
+                auth::sign(private_key, &nonce).ok_or(Error::PrivateKey)?
+            }
+            Credential::Jwt(_) => unreachable!("JWTs are presented without a challenge"),
+        };
+        request(&client_ptls, &Cmd::Authenticate { proof }).await?;
+
+        Ok(client_ptls)
+    }
+}
+
+/// Connects to the server at `addr` with a fresh ptls key.
+async fn handshake(
+    addr: &str,
+    server_public: &RsaPublicKey,
+) -> Result<Ptls<OwnedReadHalf, OwnedWriteHalf>> {
+    let client = TcpStream::connect(addr).await?;
+    let client_private =
+        RsaPrivateKey::new(&mut thread_rng(), 1024).map_err(|_| Error::Connection)?;
+
+    let mut client_ptls = Ptls::new(client.into_split(), client_private);
+    client_ptls.set_public_key(server_public.clone());
+    client_ptls
+        .send_public_key()
+        .await
+        .map_err(|_| Error::Connection)?;
+
+    Ok(client_ptls)
+}
+
+/// Creates a file only the current user can read, failing if it exists.
+fn create_secret_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|error| Error::Config(format!("cannot create {}: {error}", path.display())))
+}
+
+/// Proof key of the token for an Argon2 setting, cached in `derived`.
+fn derive(
+    derived: &Mutex<Option<DerivedKey>>,
+    secret: &str,
+    setting: &str,
+) -> Result<Secret<Vec<u8>>> {
+    let mut cached = derived.lock().unwrap();
+    if let Some((cached_setting, key)) = &*cached {
+        if cached_setting == setting {
+            return Ok(key.clone());
         }
     }
+
+    let key = auth::derive_key(secret, setting)
+        .map(Secret)
+        .ok_or(Error::UnexpectedResponse)?;
+    *cached = Some((setting.to_owned(), key.clone()));
+    Ok(key)
+}
+
+/// Sends a command on a connection that has no other traffic and waits for
+/// its response.
+async fn request(client_ptls: &Ptls<OwnedReadHalf, OwnedWriteHalf>, cmd: &Cmd) -> Result<Cmd> {
+    client_ptls
+        .send(&bincode::serialize(cmd).map_err(|_| Error::Connection)?)
+        .await
+        .map_err(|_| Error::Connection)?;
+
+    let received = client_ptls.receive().await.map_err(|_| Error::Connection)?;
+    response(bincode::deserialize(&received).map_err(|_| Error::UnexpectedResponse)?)
+}
+
+/// Converts error responses to [`Error::Server`], [`Error::Authentication`]
+/// and [`Error::QuotaExceeded`].
+fn response(cmd: Cmd) -> Result<Cmd> {
+    match cmd {
+        Cmd::Error { message } => Err(Error::Server(message)),
+        Cmd::AuthenticationFailed { message } => Err(Error::Authentication(message)),
+        Cmd::QuotaExceeded { message } => Err(Error::QuotaExceeded(message)),
+        cmd => Ok(cmd),
+    }
 }
@@ -1,10 +1,533 @@
-use client::Client;
+use clap::{Parser, Subcommand};
+use client::{repl, Client, Config, Error, ForwardConfig, Session};
 use dotenv::dotenv;
+use std::{path::PathBuf, process::ExitCode};
+use util::{AuditFilter, PermissionLevel, Quotas, RateLimits};
+
+#[derive(Parser)]
+#[command(about = "Client for TCP port forwarding")]
+struct Cli {
+    /// TOML configuration file, overridden by environment and flags
+    #[arg(long, env = "CLIENT_CONFIG", global = true)]
+    config: Option<String>,
+    /// address of the proxy server [env: HOST]
+    #[arg(long, global = true)]
+    host: Option<String>,
+    /// PKCS#1 PEM public key of the server [env: CERT]
+    #[arg(long, global = true)]
+    cert: Option<String>,
+    /// authentication token [env: TOKEN]
+    #[arg(long, global = true)]
+    token: Option<String>,
+    /// PKCS#1 PEM private key authenticating instead of the token [env:
+    /// PRIVATE_KEY]
+    #[arg(long, global = true)]
+    key: Option<String>,
+    /// JWT of the identity provider, authenticating instead of the token
+    /// [env: JWT]
+    #[arg(long, global = true)]
+    jwt: Option<String>,
+    /// TOTP code stepping up admin commands, if TOTP is enabled
+    #[arg(long, global = true)]
+    totp: Option<String>,
+    /// log filter, e.g. info or client=debug [env: LOG_LEVEL]
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// human or json [env: LOG_FORMAT]
+    #[arg(long, global = true)]
+    log_format: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Interactive prompt (default).
+    Repl,
+    /// Listens on a local port and forwards its connections to a node.
+    Forward {
+        hostname: String,
+        port: u32,
+        local_port: u16,
+        /// local address to listen on
+        #[arg(long, default_value = "localhost")]
+        bind: String,
+    },
+    /// Pipes stdin and stdout through a port of a node, for SSH
+    /// ProxyCommand.
+    Stdio { hostname: String, port: u32 },
+    /// Shares local ports on request of the server until disconnected.
+    Share,
+    /// Keeps the configured forwards up, reconnecting when the connection
+    /// drops. Also shares local ports like `share`.
+    Up,
+    /// Administrative commands.
+    #[command(subcommand)]
+    Admin(AdminCommand),
+    /// Exchanges an enrollment code for the credential of a new client and
+    /// writes it to a file that must not exist yet.
+    #[command(group = clap::ArgGroup::new("credential").required(true))]
+    Enroll {
+        code: String,
+        /// where the token is written
+        #[arg(long, group = "credential")]
+        token_file: Option<PathBuf>,
+        /// where a generated private key is written, whose public key is
+        /// registered instead of issuing a token
+        #[arg(long, group = "credential")]
+        key_file: Option<PathBuf>,
+    },
+    /// Replaces the token by a new one with the same label and lifetime and
+    /// prints it. The old token stops working.
+    RotateToken,
+    /// Reports every problem of the configuration.
+    CheckConfig,
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// Manages clients.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Lists the latest audit log entries.
+    Audit {
+        #[arg(long)]
+        actor: Option<String>,
+        #[arg(long)]
+        peer: Option<String>,
+        #[arg(long)]
+        action: Option<String>,
+        #[arg(long)]
+        target: Option<String>,
+        /// unix time in seconds
+        #[arg(long)]
+        since: Option<u64>,
+        /// unix time in seconds
+        #[arg(long)]
+        until: Option<u64>,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Lists the latest forwarding sessions.
+    Sessions {
+        /// only sessions this client requested or served
+        #[arg(long)]
+        hostname: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Views and resets transfer quota usage.
+    #[command(subcommand)]
+    Traffic(TrafficCommand),
+    /// Manages the tokens of clients.
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Manages the TOTP second factor of admin commands.
+    #[command(subcommand)]
+    Totp(TotpCommand),
+}
+
+#[derive(Subcommand)]
+enum TotpCommand {
+    /// Generates a secret and prints it with its otpauth:// URI. It replaces
+    /// the current one once confirmed.
+    Enroll,
+    /// Activates the enrolled secret with a code it generated.
+    Confirm { code: String },
+    /// Removes the secret of a client.
+    Disable { username: String },
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    /// Adds a token to a client and prints it, it is not shown again.
+    Add {
+        username: String,
+        #[arg(long, default_value = "")]
+        label: String,
+        /// unix time in seconds
+        #[arg(long)]
+        expires_at: Option<u64>,
+    },
+    /// Lists the tokens of a client.
+    List { username: String },
+    /// Revokes a token and disconnects its sessions.
+    Revoke { id: String },
+}
+
+#[derive(Subcommand)]
+enum TrafficCommand {
+    /// Lists the usage of the latest months.
+    List {
+        /// only the usage of this client
+        #[arg(long)]
+        hostname: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Resets the usage of a client in the current month.
+    Reset { username: String },
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Adds a client and prints its token, which is not shown again.
+    Add {
+        username: String,
+        /// standard, node or admin:<level>
+        #[arg(long, default_value = "standard")]
+        permission_level: PermissionLevel,
+        /// PKCS#1 PEM public key file the client authenticates with instead
+        /// of a token
+        #[arg(long)]
+        public_key: Option<String>,
+    },
+    /// Prints a single-use code with which the client enrolls itself.
+    Enroll {
+        username: String,
+        /// standard, node or admin:<level>
+        #[arg(long, default_value = "standard")]
+        permission_level: PermissionLevel,
+        /// seconds the code is valid, at most 30 days
+        #[arg(long, default_value_t = 3600)]
+        valid_for: u64,
+    },
+    /// Lists clients.
+    List {
+        /// list clients whose hostnames come after this one
+        #[arg(long, default_value = "")]
+        after: String,
+        #[arg(long, default_value_t = u64::MAX)]
+        limit: u64,
+    },
+    /// Removes a client.
+    Remove { username: String },
+    /// Registers the PKCS#1 PEM public key file of a client, or removes its
+    /// key if the file is omitted.
+    Key {
+        username: String,
+        public_key: Option<String>,
+    },
+    /// Restricts the addresses a client may authenticate from to CIDR
+    /// ranges, or lifts the restriction if none are given.
+    Sources {
+        username: String,
+        sources: Vec<String>,
+    },
+    /// Sets the bandwidth limits of a client in bytes per second. Omitted
+    /// limits are unlimited.
+    Limit {
+        username: String,
+        /// what the client sends over all its sessions
+        #[arg(long)]
+        up: Option<u64>,
+        /// what the client receives over all its sessions
+        #[arg(long)]
+        down: Option<u64>,
+        /// what the client sends in each session
+        #[arg(long)]
+        session_up: Option<u64>,
+        /// what the client receives in each session
+        #[arg(long)]
+        session_down: Option<u64>,
+    },
+    /// Sets the quotas of a client, counted both for the forwards it
+    /// requests and the ones it serves. Omitted quotas are unlimited.
+    Quota {
+        username: String,
+        /// concurrent forwards
+        #[arg(long)]
+        forwards: Option<u64>,
+        /// forward requests per minute
+        #[arg(long)]
+        requests_per_minute: Option<u64>,
+        /// bytes sent and received per calendar month
+        #[arg(long)]
+        transfer: Option<u64>,
+    },
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenv().ok();
-    util::env![CERT, TOKEN, HOST];
+    let cli = Cli::parse();
+
+    let loader = Config::layers(cli.config.as_deref())
+        .flag("host", cli.host)
+        .flag("cert", cli.cert)
+        .flag("token", cli.token)
+        .flag("key", cli.key)
+        .flag("jwt", cli.jwt)
+        .flag("log_level", cli.log_level)
+        .flag("log_format", cli.log_format);
+
+    let authenticate = !matches!(cli.command, Some(Command::Enroll { .. }));
+    let config = match Config::load(loader, authenticate) {
+        Ok(config) => config,
+        Err(problems) => {
+            eprintln!("{problems}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match cli.command.unwrap_or(Command::Repl) {
+        Command::CheckConfig => {
+            println!("configuration is valid");
+            Ok(())
+        }
+        Command::Enroll {
+            code,
+            token_file,
+            key_file,
+        } => {
+            config.logging.init();
+
+            let (path, key) = match (token_file, key_file) {
+                (_, Some(key_file)) => (key_file, true),
+                (Some(token_file), None) => (token_file, false),
+                (None, None) => unreachable!("clap requires a token or key file"),
+            };
+            Client::enroll(&config.host, &config.cert, &code, &path, key)
+                .await
+                .map(|hostname| println!("enrolled as {hostname}"))
+        }
+        command => {
+            config.logging.init();
+
+            let client = match (&config.key, &config.jwt, &config.token) {
+                (Some(key), _, _) => Client::with_key(&config.host, &config.cert, key),
+                (None, Some(jwt), _) => Client::with_jwt(&config.host, &config.cert, jwt),
+                (None, None, Some(token)) => Client::new(&config.host, &config.cert, token),
+                (None, None, None) => {
+                    unreachable!("the configuration requires a token, key or JWT")
+                }
+            };
+            match client {
+                Ok(client) => run(client, command, &config.forwards, cli.totp.as_deref()).await,
+                Err(error) => Err(error),
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            match error {
+                // the server refused the command
+                Error::Server(_) | Error::Authentication(_) | Error::QuotaExceeded(_) => {
+                    ExitCode::FAILURE
+                }
+                _ => ExitCode::from(3),
+            }
+        }
+    }
+}
+
+async fn run(
+    client: Client,
+    command: Command,
+    forwards: &[ForwardConfig],
+    totp: Option<&str>,
+) -> client::Result<()> {
+    match command {
+        Command::Repl => repl::run(client.connect().await?, forwards).await,
+        Command::Forward {
+            hostname,
+            port,
+            local_port,
+            bind,
+        } => {
+            // the connections are admitted with tickets of the session
+            let session = client.connect().await?;
+            session
+                .get_port(&hostname, port, (bind.as_str(), local_port))
+                .await?
+                .await;
+            return Err(Error::Connection);
+        }
+        Command::Stdio { hostname, port } => client.stdio(&hostname, port).await?,
+        Command::Share => {
+            client.connect().await?.closed().await;
+            return Err(Error::Connection);
+        }
+        Command::Up => client.up(forwards).await,
+        Command::CheckConfig | Command::Enroll { .. } => {}
+        Command::Admin(AdminCommand::Audit {
+            actor,
+            peer,
+            action,
+            target,
+            since,
+            until,
+            limit,
+        }) => {
+            let session = admin_session(&client, totp).await?;
+            let filter = AuditFilter {
+                actor,
+                peer,
+                action,
+                target,
+                since: since.map(|since| since * 1000),
+                until: until.map(|until| until * 1000),
+            };
+
+            for entry in session.list_audit(filter, limit).await? {
+                println!("{}", repl::format_audit_entry(&entry));
+            }
+        }
+        Command::Admin(AdminCommand::Sessions { hostname, limit }) => {
+            let session = admin_session(&client, totp).await?;
+
+            for session in session.list_sessions(hostname.as_deref(), limit).await? {
+                println!("{}", repl::format_session(&session));
+            }
+        }
+        Command::RotateToken => {
+            let token = client.connect().await?.rotate_token().await?;
+            println!("{}", token.0);
+        }
+        Command::Admin(AdminCommand::Token(command)) => {
+            let session = admin_session(&client, totp).await?;
+
+            match command {
+                TokenCommand::Add {
+                    username,
+                    label,
+                    expires_at,
+                } => {
+                    let expires_at = expires_at.map(|expires_at| expires_at * 1000);
+                    let token = session.add_token(&username, &label, expires_at).await?;
+                    println!("{}", token.0);
+                }
+                TokenCommand::List { username } => {
+                    for token in session.list_tokens(&username).await? {
+                        println!("{}", repl::format_token(&token));
+                    }
+                }
+                TokenCommand::Revoke { id } => session.revoke_token(&id).await?,
+            }
+        }
+        Command::Admin(AdminCommand::Totp(command)) => {
+            let session = admin_session(&client, totp).await?;
+
+            match command {
+                TotpCommand::Enroll => {
+                    let (secret, uri) = session.enroll_totp().await?;
+                    println!("{}\n{}", secret.0, uri.0);
+                }
+                TotpCommand::Confirm { code } => session.confirm_totp(&code).await?,
+                TotpCommand::Disable { username } => session.disable_totp(&username).await?,
+            }
+        }
+        Command::Admin(AdminCommand::Traffic(command)) => {
+            let session = admin_session(&client, totp).await?;
+
+            match command {
+                TrafficCommand::List { hostname, limit } => {
+                    for usage in session.list_traffic(hostname.as_deref(), limit).await? {
+                        println!("{}", repl::format_traffic(&usage));
+                    }
+                }
+                TrafficCommand::Reset { username } => session.reset_traffic(&username).await?,
+            }
+        }
+        Command::Admin(AdminCommand::User(command)) => {
+            let session = admin_session(&client, totp).await?;
+
+            match command {
+                UserCommand::Add {
+                    username,
+                    permission_level,
+                    public_key,
+                } => {
+                    let public_key = read_public_key(public_key.as_deref())?;
+                    let token = session
+                        .add_client(&username, permission_level, public_key)
+                        .await?;
+                    if let Some(token) = token {
+                        println!("{}", token.0);
+                    }
+                }
+                UserCommand::Enroll {
+                    username,
+                    permission_level,
+                    valid_for,
+                } => {
+                    let code = session
+                        .create_enrollment(&username, permission_level, valid_for)
+                        .await?;
+                    println!("{}", code.0);
+                }
+                UserCommand::List { after, limit } => {
+                    for client in session.list_clients(&after, limit).await? {
+                        println!("{}", repl::format_client(&client));
+                    }
+                }
+                UserCommand::Remove { username } => session.remove_client(&username).await?,
+                UserCommand::Key {
+                    username,
+                    public_key,
+                } => {
+                    let public_key = read_public_key(public_key.as_deref())?;
+                    session.set_public_key(&username, public_key).await?;
+                }
+                UserCommand::Sources { username, sources } => {
+                    let sources = (!sources.is_empty()).then_some(sources);
+                    session.set_allowed_sources(&username, sources).await?;
+                }
+                UserCommand::Limit {
+                    username,
+                    up,
+                    down,
+                    session_up,
+                    session_down,
+                } => {
+                    let limits = RateLimits {
+                        up,
+                        down,
+                        session_up,
+                        session_down,
+                    };
+                    session.set_rate_limits(&username, limits).await?
+                }
+                UserCommand::Quota {
+                    username,
+                    forwards,
+                    requests_per_minute,
+                    transfer,
+                } => {
+                    let quotas = Quotas {
+                        forwards,
+                        requests_per_minute,
+                        transfer,
+                    };
+                    session.set_quotas(&username, quotas).await?
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens a session for admin commands, stepped up with the TOTP `code` if it
+/// is given.
+async fn admin_session(client: &Client, code: Option<&str>) -> client::Result<Session> {
+    let session = client.connect().await?;
+    if let Some(code) = code {
+        session.step_up(code).await?;
+    }
+
+    Ok(session)
+}
 
-    Client::connect(*HOST, *CERT, *TOKEN).await;
+/// Reads a public key file, the server checks its format.
+fn read_public_key(path: Option<&str>) -> client::Result<Option<String>> {
+    path.map(|path| {
+        std::fs::read_to_string(path)
+            .map_err(|error| Error::Config(format!("cannot read public key {path}: {error}")))
+    })
+    .transpose()
 }
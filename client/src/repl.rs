@@ -0,0 +1,430 @@
+use crate::{Forward, ForwardConfig, Result, Session};
+use std::collections::BTreeMap;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use util::*;
+
+/// Starts `forwards` and reads commands from stdin until it is closed.
+pub async fn run(session: Session, forwards: &[ForwardConfig]) {
+    macro_rules! print {
+        ($text:expr) => {{
+            let mut stdout = io::stdout();
+            stdout.write_all($text.as_bytes()).await.ok();
+            stdout.flush().await.ok();
+        }};
+    }
+
+    let stdin = io::stdin();
+
+    let mut br = BufReader::new(stdin);
+
+    // running forwards by local address
+    let mut registry: BTreeMap<String, Forward> = BTreeMap::new();
+
+    for forward in forwards {
+        match session.forward(forward).await {
+            Ok(handle) => {
+                registry.insert(forward.bind.clone(), handle);
+            }
+            Err(error) => print!(format!("cannot forward {}: {error}\n", forward.bind)),
+        }
+    }
+
+    loop {
+        print!("proxy > ");
+        let mut line = String::new();
+        match br.read_line(&mut line).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(_) => continue,
+        }
+
+        let line = line.split_whitespace().collect::<Vec<&str>>();
+
+        // forwards that ended on their own are no longer listed
+        registry.retain(|_, forward| !forward.is_finished());
+
+        let result: Result<()> = match line.as_slice() {
+            [] => continue,
+            ["get", hostname, port, local_port] => {
+                let (Ok(port), Ok(local_port)) = (port.parse(), local_port.parse::<u16>()) else {
+                    print!("cannot parse port\n");
+                    continue;
+                };
+
+                session
+                    .get_port(hostname, port, ("localhost", local_port))
+                    .await
+                    .map(|forward| {
+                        registry.insert(format!("localhost:{local_port}"), forward);
+                    })
+            }
+            ["list"] => {
+                for forward in registry.values() {
+                    print!(format!(
+                        "{} -> {}:{} connections: {} ({} active) sent: {}B received: {}B uptime: {}s\n",
+                        forward.local(),
+                        forward.hostname(),
+                        forward.port(),
+                        forward.connections(),
+                        forward.active_connections(),
+                        forward.bytes_sent(),
+                        forward.bytes_received(),
+                        forward.uptime().as_secs()
+                    ));
+                }
+                continue;
+            }
+            ["close", local] => {
+                // a bare port stands for a `get` listener
+                let local = match local.parse::<u16>() {
+                    Ok(local_port) => format!("localhost:{local_port}"),
+                    Err(_) => local.to_string(),
+                };
+
+                if registry.remove(&local).is_none() {
+                    print!("no such forward\n");
+                    continue;
+                }
+                Ok(())
+            }
+            ["close_all"] => {
+                registry.clear();
+                Ok(())
+            }
+            ["add_usr", username] => {
+                match session
+                    .add_client(username, PermissionLevel::Standart, None)
+                    .await
+                {
+                    Ok(token) => {
+                        if let Some(token) = token {
+                            print!(format!("token: {} (not shown again)\n", token.0));
+                        }
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            ["key_usr", username, file @ ..] if file.len() <= 1 => {
+                // without a file the key is removed
+                let public_key = match file.first().map(std::fs::read_to_string) {
+                    Some(Ok(public_key)) => Some(public_key),
+                    Some(Err(error)) => {
+                        print!(format!("cannot read public key: {error}\n"));
+                        continue;
+                    }
+                    None => None,
+                };
+
+                session.set_public_key(username, public_key).await
+            }
+            ["sources_usr", username, sources @ ..] => {
+                // without ranges the restriction is lifted
+                let sources: Vec<String> =
+                    sources.iter().map(|source| source.to_string()).collect();
+
+                session
+                    .set_allowed_sources(username, (!sources.is_empty()).then_some(sources))
+                    .await
+            }
+            ["list_usr", args @ ..] if args.len() <= 2 => {
+                let after = args.first().copied().unwrap_or_default();
+                let Ok(limit) = args.get(1).map_or(Ok(u64::MAX), |limit| limit.parse()) else {
+                    print!("cannot parse limit\n");
+                    continue;
+                };
+
+                match session.list_clients(after, limit).await {
+                    Ok(clients) => {
+                        for client in clients {
+                            print!(format!("{}\n", format_client(&client)));
+                        }
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            ["limit_usr", username, limits @ ..] if limits.len() == 4 => {
+                // bytes per second, `-` for unlimited
+                let Ok(limits) = limits
+                    .iter()
+                    .map(|limit| match *limit {
+                        "-" => Ok(None),
+                        limit => limit.parse().map(Some),
+                    })
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                else {
+                    print!("cannot parse limits\n");
+                    continue;
+                };
+
+                let limits = RateLimits {
+                    up: limits[0],
+                    down: limits[1],
+                    session_up: limits[2],
+                    session_down: limits[3],
+                };
+                session.set_rate_limits(username, limits).await
+            }
+            ["quota_usr", username, forwards, requests_per_minute, transfer] => {
+                let quota = |quota: &str| match quota {
+                    "-" => Ok(None),
+                    quota => quota.parse().map(Some),
+                };
+                let (Ok(forwards), Ok(requests_per_minute), Ok(transfer)) =
+                    (quota(forwards), quota(requests_per_minute), quota(transfer))
+                else {
+                    print!("cannot parse quotas\n");
+                    continue;
+                };
+
+                let quotas = Quotas {
+                    forwards,
+                    requests_per_minute,
+                    transfer,
+                };
+                session.set_quotas(username, quotas).await
+            }
+            ["traffic", args @ ..] if args.len() <= 2 => {
+                let Ok(limit) = args.first().map_or(Ok(20), |limit| limit.parse()) else {
+                    print!("cannot parse limit\n");
+                    continue;
+                };
+
+                match session.list_traffic(args.get(1).copied(), limit).await {
+                    Ok(usage) => {
+                        for usage in usage {
+                            print!(format!("{}\n", format_traffic(&usage)));
+                        }
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            ["reset_traffic", username] => session.reset_traffic(username).await,
+            ["step_up", code] => session.step_up(code).await,
+            ["add_token", username, label, expires_at @ ..] if expires_at.len() <= 1 => {
+                // unix time in seconds
+                let Ok(expires_at) = expires_at
+                    .first()
+                    .map(|expires_at| expires_at.parse::<u64>())
+                    .transpose()
+                else {
+                    print!("cannot parse expiry\n");
+                    continue;
+                };
+
+                match session
+                    .add_token(
+                        username,
+                        label,
+                        expires_at.map(|expires_at| expires_at * 1000),
+                    )
+                    .await
+                {
+                    Ok(token) => {
+                        print!(format!("token: {} (not shown again)\n", token.0));
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            ["list_tokens", username] => match session.list_tokens(username).await {
+                Ok(tokens) => {
+                    for token in tokens {
+                        print!(format!("{}\n", format_token(&token)));
+                    }
+                    Ok(())
+                }
+                Err(error) => Err(error),
+            },
+            ["revoke_token", id] => session.revoke_token(id).await,
+            ["rotate_token"] => match session.rotate_token().await {
+                Ok(token) => {
+                    print!(format!("token: {} (not shown again)\n", token.0));
+                    Ok(())
+                }
+                Err(error) => Err(error),
+            },
+            ["list_sessions", args @ ..] if args.len() <= 2 => {
+                let Ok(limit) = args.first().map_or(Ok(20), |limit| limit.parse()) else {
+                    print!("cannot parse limit\n");
+                    continue;
+                };
+
+                match session.list_sessions(args.get(1).copied(), limit).await {
+                    Ok(sessions) => {
+                        for session in sessions {
+                            print!(format!("{}\n", format_session(&session)));
+                        }
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            ["audit", filters @ ..] => {
+                let mut filter = AuditFilter::default();
+                let mut limit = 20;
+
+                let parsed = filters.iter().try_for_each(|arg| {
+                    let (key, value) = arg.split_once('=').ok_or(())?;
+                    match key {
+                        "actor" => filter.actor = Some(value.to_string()),
+                        "peer" => filter.peer = Some(value.to_string()),
+                        "action" => filter.action = Some(value.to_string()),
+                        "target" => filter.target = Some(value.to_string()),
+                        "since" => {
+                            filter.since = Some(value.parse::<u64>().map_err(|_| ())? * 1000)
+                        }
+                        "until" => {
+                            filter.until = Some(value.parse::<u64>().map_err(|_| ())? * 1000)
+                        }
+                        "limit" => limit = value.parse().map_err(|_| ())?,
+                        _ => return Err(()),
+                    }
+                    Ok(())
+                });
+                if parsed.is_err() {
+                    print!("cannot parse filter\n");
+                    continue;
+                }
+
+                match session.list_audit(filter, limit).await {
+                    Ok(entries) => {
+                        for entry in entries {
+                            print!(format!("{}\n", format_audit_entry(&entry)));
+                        }
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            ["add_http_route", domain, hostname, port] => {
+                let Ok(port) = port.parse() else {
+                    print!("cannot parse port\n");
+                    continue;
+                };
+
+                session.add_http_route(domain, hostname, port).await
+            }
+            ["rm_http_route", domain] => session.remove_http_route(domain).await,
+            ["add_tls_route", server_name, hostname, port, termination @ ..]
+                if matches!(termination.len(), 0 | 2) =>
+            {
+                let Ok(port) = port.parse() else {
+                    print!("cannot parse port\n");
+                    continue;
+                };
+                let termination = match termination {
+                    [certificate, private_key] => Some(TlsTermination {
+                        certificate: certificate.to_string(),
+                        private_key: private_key.to_string(),
+                    }),
+                    _ => None,
+                };
+
+                session
+                    .add_tls_route(server_name, hostname, port, termination)
+                    .await
+            }
+            ["rm_tls_route", server_name] => session.remove_tls_route(server_name).await,
+            _ => {
+                print!("unknown command\n");
+                continue;
+            }
+        };
+
+        match result {
+            Ok(()) => print!("ok\n"),
+            Err(error) => print!(format!("{error}\n")),
+        }
+    }
+}
+
+/// Formats a client with its bandwidth limits, quota usage and public key
+/// fingerprint, `-` standing for unlimited or no key.
+pub fn format_client(client: &ClientInfo) -> String {
+    let limit = |limit: Option<u64>| limit.map_or("-".to_string(), |limit| format!("{limit}B/s"));
+    let quota = |quota: Option<u64>| quota.map_or("-".to_string(), |quota| quota.to_string());
+    let (limits, quotas, usage) = (&client.rate_limits, &client.quotas, &client.usage);
+
+    format!(
+        "{} {:?} up: {} down: {} session up: {} session down: {} forwards: {}/{} requests/min: {}/{} transfer: {}B/{} key: {} totp: {} sources: {}",
+        client.hostname,
+        client.permission_level,
+        limit(limits.up),
+        limit(limits.down),
+        limit(limits.session_up),
+        limit(limits.session_down),
+        usage.forwards,
+        quota(quotas.forwards),
+        usage.requests_per_minute,
+        quota(quotas.requests_per_minute),
+        usage.transfer,
+        quotas
+            .transfer
+            .map_or("-".to_string(), |transfer| format!("{transfer}B")),
+        client.key_fingerprint.as_deref().unwrap_or("-"),
+        if client.totp { "on" } else { "off" },
+        client
+            .allowed_sources
+            .as_ref()
+            .map_or("-".to_string(), |sources| sources.join(","))
+    )
+}
+
+/// Formats the transfer quota usage of a period as `period hostname used/quota`.
+pub fn format_traffic(usage: &TrafficUsage) -> String {
+    let quota = usage
+        .quota
+        .map_or("-".to_string(), |quota| format!("{quota}B"));
+
+    format!(
+        "{} {} {}B/{}",
+        usage.period, usage.hostname, usage.bytes, quota
+    )
+}
+
+/// Formats a token as `id label created: .. expires: ..`, in unix seconds.
+pub fn format_token(token: &TokenInfo) -> String {
+    let expires_at = token.expires_at.map_or("-".to_string(), |expires_at| {
+        (expires_at / 1000).to_string()
+    });
+
+    format!(
+        "{} {:?} created: {} expires: {}",
+        token.id,
+        token.label,
+        token.created_at / 1000,
+        expires_at
+    )
+}
+
+/// Formats a session as `id requester -> node:port ...`.
+pub fn format_session(session: &SessionInfo) -> String {
+    format!(
+        "{} {} -> {}:{} started: {} duration: {}s up: {}B down: {}B {}",
+        session.id,
+        session.requester,
+        session.node,
+        session.port,
+        session.started_at / 1000,
+        session.ended_at.saturating_sub(session.started_at) / 1000,
+        session.bytes_up,
+        session.bytes_down,
+        session.close_reason
+    )
+}
+
+/// Formats an audit log entry as `timestamp actor peer action target outcome`.
+pub fn format_audit_entry(entry: &AuditEntry) -> String {
+    format!(
+        "{} {} {} {} {} {}",
+        entry.timestamp / 1000,
+        entry.actor.as_deref().unwrap_or("-"),
+        entry.peer,
+        entry.action,
+        entry.target.as_deref().unwrap_or("-"),
+        entry.outcome
+    )
+}
@@ -0,0 +1,438 @@
+use crate::{response, Client, Error, Forward, ForwardConfig, Result};
+use ptls::Ptls;
+use std::{collections::VecDeque, sync::Arc};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        ToSocketAddrs,
+    },
+    sync::{oneshot, watch, Mutex},
+    task::JoinHandle,
+};
+use tracing::{info_span, warn, Instrument};
+use util::*;
+
+/// Authenticated control connection. Closed when dropped, forwards opened
+/// through it keep running until their own handles are dropped.
+pub struct Session {
+    client: Client,
+    control: Arc<Control>,
+    receiver: JoinHandle<()>,
+    closed: watch::Receiver<bool>,
+}
+
+/// Sending side of a control connection, also used by the [`Client`] to get
+/// tickets for its data connections.
+pub(crate) struct Control {
+    client_ptls: Arc<Ptls<OwnedReadHalf, OwnedWriteHalf>>,
+    /// Waiters of the responses, in the order of the sent commands.
+    pending: Arc<Mutex<VecDeque<oneshot::Sender<Cmd>>>>,
+}
+
+impl Session {
+    pub(crate) fn new(client: Client, client_ptls: Ptls<OwnedReadHalf, OwnedWriteHalf>) -> Self {
+        let control = Arc::new(Control {
+            client_ptls: Arc::new(client_ptls),
+            pending: Arc::default(),
+        });
+        client.set_control(&control);
+        let (close, closed) = watch::channel(false);
+
+        let receiver = tokio::spawn({
+            let client = client.clone();
+            let client_ptls = Arc::clone(&control.client_ptls);
+            let pending = Arc::clone(&control.pending);
+
+            async move {
+                while let Ok(received) = client_ptls.receive().await {
+                    let cmd: Cmd = if let Ok(cmd) = bincode::deserialize(&received) {
+                        cmd
+                    } else {
+                        continue;
+                    };
+
+                    match cmd {
+                        // share requests are pushed by the server at any time
+                        Cmd::SharePort { port, id, protocol } => {
+                            let client = client.clone();
+
+                            let span = info_span!("share", session_id = id, port, ?protocol);
+                            tokio::spawn(
+                                async move {
+                                    match client.share(port, id, protocol).await {
+                                        Ok(forward) => forward.await,
+                                        Err(error) => warn!(%error, "cannot share"),
+                                    }
+                                }
+                                .instrument(span),
+                            );
+                        }
+                        response => {
+                            if let Some(waiter) = pending.lock().await.pop_front() {
+                                waiter.send(response).ok();
+                            }
+                        }
+                    }
+                }
+
+                // fails the waiting requests
+                pending.lock().await.clear();
+                close.send(true).ok();
+            }
+        });
+
+        Self {
+            client,
+            control,
+            receiver,
+            closed,
+        }
+    }
+
+    /// Listens on `local_addr` and forwards each accepted connection to
+    /// `port` of the node `hostname`.
+    pub async fn get_port<A: ToSocketAddrs>(
+        &self,
+        hostname: &str,
+        port: u32,
+        local_addr: A,
+    ) -> Result<Forward> {
+        self.client.get_port(hostname, port, local_addr).await
+    }
+
+    /// Starts a forwarding described in the configuration file.
+    pub async fn forward(&self, config: &ForwardConfig) -> Result<Forward> {
+        self.client.forward(config).await
+    }
+
+    /// Answers the share request `id` by forwarding local `port`.
+    pub async fn share(&self, port: u32, id: u64, protocol: Protocol) -> Result<Forward> {
+        self.client.share(port, id, protocol).await
+    }
+
+    /// Adds a client. Returns its token generated by the server, which is
+    /// not shown again, or `None` if the client authenticates with the PEM
+    /// `public_key`.
+    pub async fn add_client(
+        &self,
+        username: &str,
+        permission_level: PermissionLevel,
+        public_key: Option<String>,
+    ) -> Result<Option<Secret<String>>> {
+        match self
+            .request(Cmd::AddClient {
+                username: username.to_owned(),
+                permission_level,
+                public_key,
+            })
+            .await?
+        {
+            Cmd::Token { token } => Ok(Some(token)),
+            Cmd::Ok => Ok(None),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Mints a single-use code that enrolls the client `username` with
+    /// `permission_level` for `valid_for` seconds.
+    pub async fn create_enrollment(
+        &self,
+        username: &str,
+        permission_level: PermissionLevel,
+        valid_for: u64,
+    ) -> Result<Secret<String>> {
+        match self
+            .request(Cmd::CreateEnrollment {
+                hostname: username.to_owned(),
+                permission_level,
+                valid_for,
+            })
+            .await?
+        {
+            Cmd::EnrollmentCode { code } => Ok(code),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Registers the PEM public key of a client, or removes it.
+    pub async fn set_public_key(&self, username: &str, public_key: Option<String>) -> Result<()> {
+        self.command(Cmd::SetPublicKey {
+            username: username.to_owned(),
+            public_key,
+        })
+        .await
+    }
+
+    /// Restricts the addresses a client may authenticate from to CIDR ranges,
+    /// or lifts the restriction.
+    pub async fn set_allowed_sources(
+        &self,
+        username: &str,
+        sources: Option<Vec<String>>,
+    ) -> Result<()> {
+        self.command(Cmd::SetAllowedSources {
+            username: username.to_owned(),
+            sources,
+        })
+        .await
+    }
+
+    /// Adds a token to a client. Returns the token, which is not shown
+    /// again. `expires_at` is unix time in milliseconds.
+    pub async fn add_token(
+        &self,
+        username: &str,
+        label: &str,
+        expires_at: Option<u64>,
+    ) -> Result<Secret<String>> {
+        match self
+            .request(Cmd::AddToken {
+                username: username.to_owned(),
+                label: label.to_owned(),
+                expires_at,
+            })
+            .await?
+        {
+            Cmd::Token { token } => Ok(token),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Lists the tokens of a client.
+    pub async fn list_tokens(&self, username: &str) -> Result<Vec<TokenInfo>> {
+        match self
+            .request(Cmd::ListTokens {
+                username: username.to_owned(),
+            })
+            .await?
+        {
+            Cmd::Tokens { tokens } => Ok(tokens),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Revokes a token, its sessions are disconnected.
+    pub async fn revoke_token(&self, id: &str) -> Result<()> {
+        self.command(Cmd::RevokeToken { id: id.to_owned() }).await
+    }
+
+    /// Replaces the token of the session by a new one, which later
+    /// connections authenticate with. Returns the new token, which is not
+    /// shown again.
+    pub async fn rotate_token(&self) -> Result<Secret<String>> {
+        match self.request(Cmd::RotateToken).await? {
+            Cmd::Token { token } => {
+                self.client.replace_token(&token);
+                Ok(token)
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Proves a TOTP code, admitting admin commands for the grace window of
+    /// the server.
+    pub async fn step_up(&self, code: &str) -> Result<()> {
+        self.command(Cmd::StepUp {
+            code: code.to_owned(),
+        })
+        .await
+    }
+
+    /// Generates a TOTP secret, active once confirmed with
+    /// [`Session::confirm_totp`]. Returns the base32 secret and its
+    /// `otpauth://` URI.
+    pub async fn enroll_totp(&self) -> Result<(Secret<String>, Secret<String>)> {
+        match self.request(Cmd::EnrollTotp).await? {
+            Cmd::TotpSecret { secret, uri } => Ok((secret, uri)),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Activates the TOTP secret of the last enrollment with a code of it.
+    pub async fn confirm_totp(&self, code: &str) -> Result<()> {
+        self.command(Cmd::ConfirmTotp {
+            code: code.to_owned(),
+        })
+        .await
+    }
+
+    pub async fn disable_totp(&self, username: &str) -> Result<()> {
+        self.command(Cmd::DisableTotp {
+            username: username.to_owned(),
+        })
+        .await
+    }
+
+    pub async fn remove_client(&self, username: &str) -> Result<()> {
+        self.command(Cmd::RemoveClient {
+            username: username.to_owned(),
+        })
+        .await
+    }
+
+    /// Replaces the bandwidth limits of a client.
+    pub async fn set_rate_limits(&self, username: &str, limits: RateLimits) -> Result<()> {
+        self.command(Cmd::SetRateLimits {
+            username: username.to_owned(),
+            limits,
+        })
+        .await
+    }
+
+    /// Replaces the quotas of a client.
+    pub async fn set_quotas(&self, username: &str, quotas: Quotas) -> Result<()> {
+        self.command(Cmd::SetQuotas {
+            username: username.to_owned(),
+            quotas,
+        })
+        .await
+    }
+
+    /// Lists at most `limit` clients whose hostnames come after `after`.
+    pub async fn list_clients(&self, after: &str, limit: u64) -> Result<Vec<ClientInfo>> {
+        match self
+            .request(Cmd::ListClients {
+                after: after.to_owned(),
+                limit,
+            })
+            .await?
+        {
+            Cmd::Clients { clients } => Ok(clients),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Lists the latest `limit` forwarding sessions, only the ones
+    /// `hostname` requested or served if given.
+    pub async fn list_sessions(
+        &self,
+        hostname: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<SessionInfo>> {
+        match self
+            .request(Cmd::ListSessions {
+                hostname: hostname.map(str::to_owned),
+                limit,
+            })
+            .await?
+        {
+            Cmd::Sessions { sessions } => Ok(sessions),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Lists the transfer quota usage of the latest `limit` periods, only
+    /// the usage of `hostname` if given.
+    pub async fn list_traffic(
+        &self,
+        hostname: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<TrafficUsage>> {
+        match self
+            .request(Cmd::ListTraffic {
+                hostname: hostname.map(str::to_owned),
+                limit,
+            })
+            .await?
+        {
+            Cmd::Traffic { usage } => Ok(usage),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Resets the transfer quota usage of a client in the current period.
+    pub async fn reset_traffic(&self, username: &str) -> Result<()> {
+        self.command(Cmd::ResetTraffic {
+            username: username.to_owned(),
+        })
+        .await
+    }
+
+    /// Lists the latest `limit` audit log entries matching `filter`.
+    pub async fn list_audit(&self, filter: AuditFilter, limit: u64) -> Result<Vec<AuditEntry>> {
+        match self.request(Cmd::ListAudit { filter, limit }).await? {
+            Cmd::Audit { entries } => Ok(entries),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn add_http_route(&self, domain: &str, hostname: &str, port: u32) -> Result<()> {
+        self.command(Cmd::AddHttpRoute {
+            domain: domain.to_owned(),
+            hostname: hostname.to_owned(),
+            port,
+        })
+        .await
+    }
+
+    pub async fn remove_http_route(&self, domain: &str) -> Result<()> {
+        self.command(Cmd::RemoveHttpRoute {
+            domain: domain.to_owned(),
+        })
+        .await
+    }
+
+    pub async fn add_tls_route(
+        &self,
+        server_name: &str,
+        hostname: &str,
+        port: u32,
+        termination: Option<TlsTermination>,
+    ) -> Result<()> {
+        self.command(Cmd::AddTlsRoute {
+            server_name: server_name.to_owned(),
+            hostname: hostname.to_owned(),
+            port,
+            termination,
+        })
+        .await
+    }
+
+    pub async fn remove_tls_route(&self, server_name: &str) -> Result<()> {
+        self.command(Cmd::RemoveTlsRoute {
+            server_name: server_name.to_owned(),
+        })
+        .await
+    }
+
+    /// Resolves when the control connection is closed.
+    pub async fn closed(&self) {
+        self.closed.clone().wait_for(|closed| *closed).await.ok();
+    }
+
+    /// Sends a command that is answered with [`Cmd::Ok`].
+    async fn command(&self, cmd: Cmd) -> Result<()> {
+        match self.request(cmd).await? {
+            Cmd::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    async fn request(&self, cmd: Cmd) -> Result<Cmd> {
+        self.control.request(cmd).await
+    }
+}
+
+impl Control {
+    /// Sends a command and waits for its response.
+    pub(crate) async fn request(&self, cmd: Cmd) -> Result<Cmd> {
+        let (waiter, response_receiver) = oneshot::channel();
+
+        // the lock keeps the waiters in the order of the sent commands
+        let mut pending = self.pending.lock().await;
+        self.client_ptls
+            .send(&bincode::serialize(&cmd).map_err(|_| Error::Connection)?)
+            .await
+            .map_err(|_| Error::Connection)?;
+        pending.push_back(waiter);
+        drop(pending);
+
+        response(response_receiver.await.map_err(|_| Error::Connection)?)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{forward::Stats, Client};
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        lookup_host,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        UdpSocket,
+    },
+    sync::mpsc,
+    task::JoinSet,
+};
+use util::Protocol;
+
+/// Associations without traffic from the local peer are closed after this
+/// duration.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// Relays datagrams received on `socket`, each local peer through its own
+/// stream to `port` of the node `hostname`.
+pub(crate) async fn listen(
+    client: Arc<Client>,
+    socket: UdpSocket,
+    hostname: String,
+    port: u32,
+    stats: Arc<Stats>,
+) {
+    let socket = Arc::new(socket);
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    // aborts the associations when the listener is closed
+    let mut associations = JoinSet::new();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let Ok((n, peer)) = received else {
+                    break;
+                };
+
+                peers.retain(|_, sender| !sender.is_closed());
+                let sender = peers.entry(peer).or_insert_with(|| {
+                    let (sender, receiver) = mpsc::channel(64);
+                    associations.spawn(associate(
+                        Arc::clone(&client),
+                        Arc::clone(&socket),
+                        peer,
+                        receiver,
+                        hostname.clone(),
+                        port,
+                        Arc::clone(&stats),
+                    ));
+
+                    sender
+                });
+
+                // datagrams are dropped while the stream is congested
+                sender.try_send(buf[..n].to_vec()).ok();
+            }
+            Some(_) = associations.join_next() => {}
+        }
+    }
+}
+
+async fn associate(
+    client: Arc<Client>,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    hostname: String,
+    port: u32,
+    stats: Arc<Stats>,
+) {
+    let Ok((mut r, mut w)) = client.open(&hostname, port, Protocol::Udp).await else {
+        return;
+    };
+    stats.start();
+
+    tokio::join!(
+        async {
+            while let Ok(Some(datagram)) = tokio::time::timeout(IDLE_TIMEOUT, receiver.recv()).await
+            {
+                stats.add_sent(datagram.len());
+                if write_frame(&mut w, &datagram).await.is_err() {
+                    break;
+                }
+            }
+            w.shutdown().await.ok();
+        },
+        async {
+            while let Some(datagram) = read_frame(&mut r).await {
+                stats.add_received(datagram.len());
+                socket.send_to(&datagram, peer).await.ok();
+            }
+        }
+    );
+
+    stats.end();
+}
+
+/// Relays frames of a share stream to the local UDP `port`.
+pub(crate) async fn share(
+    (mut r, mut w): (OwnedReadHalf, OwnedWriteHalf),
+    socket: UdpSocket,
+    stats: Arc<Stats>,
+) {
+    stats.start();
+
+    tokio::select! {
+        _ = async {
+            while let Some(datagram) = read_frame(&mut r).await {
+                stats.add_received(datagram.len());
+                socket.send(&datagram).await.ok();
+            }
+        } => {}
+        _ = async {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            while let Ok(n) = socket.recv(&mut buf).await {
+                stats.add_sent(n);
+                if write_frame(&mut w, &buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        } => {}
+    }
+
+    stats.end();
+}
+
+/// Connects a UDP socket to the local `port`.
+pub(crate) async fn connect_local(port: u32) -> io::Result<UdpSocket> {
+    let target = lookup_host(format!("localhost:{port}"))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cannot resolve localhost"))?;
+
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(target).await?;
+
+    Ok(socket)
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> Option<Vec<u8>> {
+    let length = r.read_u16().await.ok()?;
+
+    let mut datagram = vec![0; length as usize];
+    r.read_exact(&mut datagram).await.ok()?;
+
+    Some(datagram)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, datagram: &[u8]) -> io::Result<()> {
+    w.write_u16(datagram.len() as u16).await?;
+    w.write_all(datagram).await
+}
@@ -0,0 +1 @@
+CREATE TABLE http_routes (domain TEXT NOT NULL PRIMARY KEY, hostname TEXT NOT NULL, port INTEGER NOT NULL);
@@ -0,0 +1 @@
+CREATE TABLE tls_routes (server_name TEXT NOT NULL PRIMARY KEY, hostname TEXT NOT NULL, port INTEGER NOT NULL, certificate TEXT, private_key TEXT);
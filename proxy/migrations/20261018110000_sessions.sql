@@ -0,0 +1,3 @@
+CREATE TABLE sessions (id INTEGER PRIMARY KEY AUTOINCREMENT, share_id INTEGER NOT NULL, requester TEXT NOT NULL, node TEXT NOT NULL, port INTEGER NOT NULL, started_at INTEGER NOT NULL, ended_at INTEGER NOT NULL, bytes_up INTEGER NOT NULL, bytes_down INTEGER NOT NULL, close_reason TEXT NOT NULL);
+CREATE INDEX sessions_requester ON sessions (requester);
+CREATE INDEX sessions_node ON sessions (node);
@@ -0,0 +1,5 @@
+CREATE TABLE audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT, timestamp INTEGER NOT NULL, actor TEXT, peer TEXT NOT NULL, action TEXT NOT NULL, target TEXT, outcome TEXT NOT NULL);
+CREATE INDEX audit_log_actor ON audit_log (actor);
+CREATE INDEX audit_log_timestamp ON audit_log (timestamp);
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END;
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END;
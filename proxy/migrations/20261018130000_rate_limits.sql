@@ -0,0 +1,4 @@
+ALTER TABLE clients ADD COLUMN rate_up INTEGER;
+ALTER TABLE clients ADD COLUMN rate_down INTEGER;
+ALTER TABLE clients ADD COLUMN session_rate_up INTEGER;
+ALTER TABLE clients ADD COLUMN session_rate_down INTEGER;
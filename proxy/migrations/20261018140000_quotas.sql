@@ -0,0 +1,2 @@
+ALTER TABLE clients ADD COLUMN max_forwards INTEGER;
+ALTER TABLE clients ADD COLUMN max_requests_per_minute INTEGER;
@@ -0,0 +1,2 @@
+ALTER TABLE clients ADD COLUMN max_transfer INTEGER;
+CREATE TABLE traffic (hostname TEXT NOT NULL, period TEXT NOT NULL, bytes INTEGER NOT NULL DEFAULT 0, warned INTEGER NOT NULL DEFAULT 0, PRIMARY KEY (hostname, period));
@@ -0,0 +1,4 @@
+CREATE TABLE tokens (id TEXT PRIMARY KEY, hostname TEXT NOT NULL, hash TEXT NOT NULL);
+CREATE INDEX tokens_hostname ON tokens (hostname);
+INSERT OR IGNORE INTO tokens SELECT hostname, hostname, key FROM clients;
+ALTER TABLE clients DROP COLUMN key;
@@ -0,0 +1,2 @@
+ALTER TABLE clients ADD COLUMN public_key TEXT;
+CREATE UNIQUE INDEX clients_public_key ON clients (public_key);
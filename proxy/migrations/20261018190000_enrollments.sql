@@ -0,0 +1 @@
+CREATE TABLE enrollments (code_hash TEXT PRIMARY KEY NOT NULL, hostname TEXT NOT NULL, permission_level BLOB NOT NULL, expires_at INTEGER NOT NULL);
@@ -0,0 +1,3 @@
+ALTER TABLE clients ADD COLUMN totp_secret BLOB;
+ALTER TABLE clients ADD COLUMN totp_pending BLOB;
+ALTER TABLE clients ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0;
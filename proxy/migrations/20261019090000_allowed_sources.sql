@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN allowed_sources TEXT;
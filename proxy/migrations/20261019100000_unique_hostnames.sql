@@ -0,0 +1,2 @@
+DELETE FROM clients WHERE rowid NOT IN (SELECT MIN(rowid) FROM clients GROUP BY hostname);
+CREATE UNIQUE INDEX clients_hostname ON clients (hostname);
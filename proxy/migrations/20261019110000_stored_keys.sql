@@ -0,0 +1,2 @@
+ALTER TABLE tokens RENAME COLUMN hash TO setting;
+ALTER TABLE tokens ADD COLUMN stored_key BLOB;
@@ -0,0 +1,240 @@
+use rsa::{pkcs1::DecodeRsaPrivateKey, RsaPrivateKey};
+use serde::Deserialize;
+use util::{
+    config::{Loader, Problems},
+    logging::Logging,
+};
+
+/// Configuration of the proxy binary.
+pub struct Config {
+    /// sqlite database url
+    pub database_url: String,
+    /// PKCS#1 PEM private key file of the server
+    pub cert: Option<String>,
+    /// Address of the command and forwarding listener.
+    pub host: Option<String>,
+    /// Address of the HTTP virtual-host listener.
+    pub http_host: Option<String>,
+    /// Address of the TLS SNI listener.
+    pub tls_host: Option<String>,
+    /// Address of the Prometheus metrics listener.
+    pub metrics_host: Option<String>,
+    /// JSONL file the audit log is also appended to.
+    pub audit_file: Option<String>,
+    /// Thresholds in percent of the transfer quotas that are warned about.
+    pub traffic_warnings: Vec<u8>,
+    /// Whether live sessions are terminated once a transfer quota is used up.
+    pub terminate_over_quota: bool,
+    /// Seconds a TOTP step-up admits admin commands.
+    pub totp_grace: u64,
+    /// Seconds a connection has to authenticate before it is dropped.
+    pub auth_timeout: u64,
+    /// Connections that may be unauthenticated at the same time.
+    pub max_unauthenticated: usize,
+    /// Verification of JWT bearer tokens, if they are accepted.
+    pub jwt: Option<JwtConfig>,
+    pub logging: Logging,
+}
+
+/// The `[jwt]` table, accepting JWTs of an identity provider in place of
+/// tokens.
+///
+/// ```toml
+/// [jwt]
+/// jwks = "/etc/proxy/jwks.json"
+/// issuer = "https://idp.example"
+/// audience = "proxy"
+///
+/// [[jwt.group]]
+/// name = "proxy-admins"
+/// permission_level = "admin:1"
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// JWKS file with the public keys of the identity provider.
+    pub jwks: String,
+    /// Required `iss` claim.
+    pub issuer: String,
+    /// Required `aud` claim.
+    pub audience: String,
+    /// Claim holding the hostname of the client.
+    #[serde(default = "JwtConfig::default_hostname_claim")]
+    pub hostname_claim: String,
+    /// Claim holding the groups of the client, a string or an array.
+    #[serde(default = "JwtConfig::default_groups_claim")]
+    pub groups_claim: String,
+    /// Permission levels of groups, the first group in this order that the
+    /// token has applies.
+    #[serde(default, rename = "group")]
+    pub groups: Vec<GroupConfig>,
+    /// Level of tokens without a listed group, which are refused if unset.
+    pub permission_level: Option<String>,
+    /// Seconds of clock skew tolerated in `exp` and `nbf`.
+    #[serde(default = "JwtConfig::default_leeway")]
+    pub leeway: u64,
+}
+
+/// Permission level of the clients in a group.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub name: String,
+    /// standard, node or admin:<level>
+    pub permission_level: String,
+}
+
+impl JwtConfig {
+    fn default_hostname_claim() -> String {
+        String::from("sub")
+    }
+
+    fn default_groups_claim() -> String {
+        String::from("groups")
+    }
+
+    fn default_leeway() -> u64 {
+        60
+    }
+}
+
+impl Config {
+    /// Defaults, the file at `path` and the environment. Command line flags
+    /// are added on top by the caller.
+    pub fn layers(path: Option<&str>) -> Loader {
+        Logging::layers(Loader::new())
+            .fallback("database_url", "sqlite://server.db")
+            .fallback("traffic_warnings", vec![80, 90])
+            .fallback("terminate_over_quota", false)
+            .fallback("totp_grace", 300)
+            .fallback("auth_timeout", 10)
+            .fallback("max_unauthenticated", 256)
+            .file(path)
+            .env("database_url", "DATABASE_URL")
+            .env("cert", "CERT")
+            .env("host", "HOST")
+            .env("http_host", "HTTP_HOST")
+            .env("tls_host", "TLS_HOST")
+            .env("metrics_host", "METRICS_HOST")
+            .env("audit_file", "AUDIT_FILE")
+            .env("traffic_warnings", "TRAFFIC_WARNINGS")
+            .env("terminate_over_quota", "TERMINATE_OVER_QUOTA")
+            .env("totp_grace", "TOTP_GRACE")
+            .env("auth_timeout", "AUTH_TIMEOUT")
+            .env("max_unauthenticated", "MAX_UNAUTHENTICATED")
+    }
+
+    /// Reads the configuration, requiring the keys of the server if `serve`
+    /// is set.
+    pub fn load(mut loader: Loader, serve: bool) -> Result<Self, Problems> {
+        let database_url = loader.require::<String>("database_url");
+        let (cert, host) = if serve {
+            (loader.require::<String>("cert"), loader.require("host"))
+        } else {
+            (loader.get::<String>("cert"), loader.get("host"))
+        };
+        let http_host = loader.get::<String>("http_host");
+        let tls_host = loader.get::<String>("tls_host");
+        let metrics_host = loader.get::<String>("metrics_host");
+        let audit_file = loader.get::<String>("audit_file");
+        let traffic_warnings = loader.require::<Vec<u8>>("traffic_warnings");
+        let terminate_over_quota = loader.require::<bool>("terminate_over_quota");
+        let totp_grace = loader.require::<u64>("totp_grace");
+        let auth_timeout = loader.require::<u64>("auth_timeout");
+        let max_unauthenticated = loader.require::<usize>("max_unauthenticated");
+        let jwt = loader.get::<JwtConfig>("jwt");
+        let logging = Logging::load(&mut loader);
+
+        if let Some(database_url) = &database_url {
+            if !database_url.starts_with("sqlite:") {
+                loader.problem("database_url", "is not a sqlite url");
+            }
+        }
+
+        if let Some(cert) = &cert {
+            if let Err(error) = RsaPrivateKey::read_pkcs1_pem_file(cert) {
+                loader.problem(
+                    "cert",
+                    format!("cannot read PKCS#1 PEM private key: {error}"),
+                );
+            }
+        }
+
+        for (key, address) in [
+            ("host", &host),
+            ("http_host", &http_host),
+            ("tls_host", &tls_host),
+            ("metrics_host", &metrics_host),
+        ] {
+            if let Some(address) = address {
+                if !util::config::is_address(address) {
+                    loader.problem(key, "is not an address of the form host:port");
+                }
+            }
+        }
+
+        if let Some(traffic_warnings) = &traffic_warnings {
+            if traffic_warnings
+                .iter()
+                .any(|threshold| !(1..=100).contains(threshold))
+            {
+                loader.problem("traffic_warnings", "thresholds must be between 1 and 100");
+            }
+        }
+
+        for (key, value) in [
+            ("auth_timeout", auth_timeout),
+            (
+                "max_unauthenticated",
+                max_unauthenticated.map(|max| max as u64),
+            ),
+        ] {
+            if value == Some(0) {
+                loader.problem(key, "must be at least 1");
+            }
+        }
+
+        if let Some(jwt) = &jwt {
+            if let Err(error) = crate::jwt::Verifier::new(jwt) {
+                loader.problem("jwt", error);
+            }
+        }
+
+        let problems = loader.finish();
+        match (
+            database_url,
+            traffic_warnings,
+            terminate_over_quota,
+            totp_grace,
+            auth_timeout,
+            max_unauthenticated,
+            logging,
+        ) {
+            (
+                Some(database_url),
+                Some(traffic_warnings),
+                Some(terminate_over_quota),
+                Some(totp_grace),
+                Some(auth_timeout),
+                Some(max_unauthenticated),
+                Some(logging),
+            ) if problems.is_empty() => Ok(Self {
+                database_url,
+                cert,
+                host,
+                http_host,
+                tls_host,
+                metrics_host,
+                audit_file,
+                traffic_warnings,
+                terminate_over_quota,
+                totp_grace,
+                auth_timeout,
+                max_unauthenticated,
+                jwt,
+                logging,
+            }),
+            _ => Err(problems),
+        }
+    }
+}
@@ -1,12 +1,29 @@
+use crate::server::quota::ForwardPermit;
+use tokio::io::{AsyncRead, AsyncWrite};
+
 /// State of the connection.
 #[derive(Debug)]
 pub enum ConnectionState {
     /// A conneciton that has not yet been authorized.
     Socket,
+    /// A connection that has been challenged to prove its token or key.
+    Challenged {
+        credential: Credential,
+        nonce: Vec<u8>,
+        issued: std::time::Instant,
+    },
     /// An authorized connection that waiting for being converted to cmd or forward socket.
     Authorized {
         hostname: String,
         permission_level: util::PermissionLevel,
+        /// Token the connection authenticated with, directly or through a
+        /// ticket, so it can be disconnected when the token is revoked.
+        token_id: Option<String>,
+        /// Expiry of the JWT the connection authenticated with in unix
+        /// milliseconds, when it is disconnected.
+        expires_at: Option<u64>,
+        /// When the client last proved a TOTP code with `StepUp`.
+        stepped_up: Option<std::time::Instant>,
     },
     /// A connection, shares/receives ports or sends commands.
     PortForward {
@@ -17,14 +34,50 @@ pub enum ConnectionState {
         port: u32,
         /// unique id of the routing request
         id: u64,
+        /// Quota usage of a requested forward.
+        permit: Option<ForwardPermit>,
+        /// Token the connection authenticated with, the forward ends when it
+        /// is revoked.
+        token_id: Option<String>,
     },
 }
 
+/// What a challenged connection has to prove.
+#[derive(Debug)]
+pub enum Credential {
+    /// The token with this id.
+    Token(String),
+    /// The private key of this normalized PEM public key.
+    PublicKey(String),
+}
+
 /// Type of the port forwarding connection.
 #[derive(Debug)]
 pub enum ForwardKind {
     /// The connection shares its port.
     Share,
-    /// The connection receives a port.
-    Receive,
+    /// The connection receives a port of the node `node`.
+    Receive { node: String },
+}
+
+/// A requester stream waiting for the node that answers its share request.
+pub struct Offer {
+    /// Hostname of the requesting client or the peer address of a routed
+    /// connection.
+    pub requester: String,
+    pub node: String,
+    pub port: u32,
+    pub receiver: Receiver,
+    /// Quota usage of the forward, released with the offer once the session
+    /// is closed.
+    pub permit: Option<ForwardPermit>,
+    /// Token of the requesting client, the session ends when it is revoked.
+    pub token_id: Option<String>,
 }
+
+/// Read and write halves of a connection that receives a forwarded port.
+/// Boxed since it can be a plain TCP, a ptls or a terminated TLS stream.
+pub type Receiver = (
+    Box<dyn AsyncRead + Unpin + Send>,
+    Box<dyn AsyncWrite + Unpin + Send>,
+);
@@ -0,0 +1,1004 @@
+use crate::{sources, token};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool};
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use util::{
+    AuditEntry, AuditFilter, ClientInfo, PermissionLevel, QuotaUsage, Quotas, RateLimits,
+    SessionInfo, TokenInfo, TrafficUsage,
+};
+
+/// Connects to the sqlite database at `url`, creating and migrating it if
+/// needed.
+pub async fn connect(url: &str) -> SqlitePool {
+    let options = SqliteConnectOptions::from_str(url)
+        .unwrap()
+        .create_if_missing(true);
+
+    let database = SqlitePool::connect_with(options)
+        .await
+        .expect("Cannot connect sqlite database");
+
+    sqlx::migrate!("./migrations").run(&database).await.ok();
+    convert_tokens(&database)
+        .await
+        .expect("Cannot convert tokens");
+
+    database
+}
+
+/// Replaces the plaintext tokens and full Argon2 hashes carried over from
+/// older databases, which are kept in `setting` until then, by settings and
+/// stored keys.
+async fn convert_tokens(sqlite: &SqlitePool) -> sqlx::Result<()> {
+    let tokens = sqlx::query!("SELECT id, setting FROM tokens WHERE stored_key IS NULL;")
+        .fetch_all(sqlite)
+        .await?;
+
+    for stored in tokens {
+        let hash = if token::is_hash(&stored.setting) {
+            stored.setting
+        } else {
+            token::hash(&stored.setting)
+        };
+        let Some((setting, stored_key)) = token::stored_key(&hash) else {
+            continue;
+        };
+        sqlx::query!(
+            "UPDATE tokens SET setting = ?, stored_key = ? WHERE id = ?;",
+            setting,
+            stored_key,
+            stored.id
+        )
+        .execute(sqlite)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Adds a client with a generated token, or with the registered
+/// `public_key` and no token. Returns the token, only its hash is stored, or
+/// `None` if the hostname is taken.
+pub async fn add_client(
+    sqlite: &SqlitePool,
+    username: &str,
+    permission_level: &PermissionLevel,
+    public_key: Option<&str>,
+) -> sqlx::Result<Option<Option<String>>> {
+    let blob = bincode::serialize(permission_level).unwrap();
+
+    let mut transaction = sqlite.begin().await?;
+    let client = sqlx::query!("SELECT hostname FROM clients WHERE hostname = ?;", username)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    if client.is_some() {
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "INSERT INTO clients (hostname, permission_level, public_key) VALUES (?, ?, ?);",
+        username,
+        blob,
+        public_key
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    if public_key.is_some() {
+        transaction.commit().await?;
+        return Ok(Some(None));
+    }
+
+    let (_, token) = insert_token(&mut transaction, username, "", None).await?;
+    transaction.commit().await?;
+
+    Ok(Some(Some(token)))
+}
+
+/// Inserts a generated token of `hostname`. Returns its id and the token.
+async fn insert_token(
+    connection: &mut SqliteConnection,
+    hostname: &str,
+    label: &str,
+    expires_at: Option<u64>,
+) -> sqlx::Result<(String, String)> {
+    let (id, token) = token::generate();
+    let (_, secret) = util::auth::split_token(&token).unwrap();
+    let secret = secret.to_string();
+    let (setting, stored_key) =
+        tokio::task::spawn_blocking(move || token::stored_key(&token::hash(&secret)))
+            .await
+            .unwrap()
+            .expect("generated hashes are valid");
+
+    let created_at = now() as i64;
+    let expires_at = expires_at.map(|expires_at| i64::try_from(expires_at).unwrap_or(i64::MAX));
+    sqlx::query!(
+        "INSERT INTO tokens (id, hostname, setting, stored_key, label, created_at, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?);",
+        id,
+        hostname,
+        setting,
+        stored_key,
+        label,
+        created_at,
+        expires_at
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    Ok((id, token))
+}
+
+/// Adds a token to a client. Returns the token, or `None` if there is no
+/// such client.
+pub async fn add_token(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    label: &str,
+    expires_at: Option<u64>,
+) -> sqlx::Result<Option<String>> {
+    let mut transaction = sqlite.begin().await?;
+    let client = sqlx::query!("SELECT hostname FROM clients WHERE hostname = ?;", hostname)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    if client.is_none() {
+        return Ok(None);
+    }
+
+    let (_, token) = insert_token(&mut transaction, hostname, label, expires_at).await?;
+    transaction.commit().await?;
+
+    Ok(Some(token))
+}
+
+/// Lists the tokens of a client, oldest first.
+pub async fn list_tokens(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<Vec<TokenInfo>> {
+    let tokens = sqlx::query!(
+        r#"SELECT id AS "id!", label, created_at, expires_at FROM tokens WHERE hostname = ?
+         ORDER BY created_at, id;"#,
+        hostname
+    )
+    .fetch_all(sqlite)
+    .await?;
+
+    Ok(tokens
+        .into_iter()
+        .map(|token| TokenInfo {
+            id: token.id,
+            label: token.label,
+            created_at: token.created_at as u64,
+            expires_at: token.expires_at.map(|expires_at| expires_at as u64),
+        })
+        .collect())
+}
+
+/// Deletes the token `id`. Returns whether it existed.
+pub async fn revoke_token(sqlite: &SqlitePool, id: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query!("DELETE FROM tokens WHERE id = ?;", id)
+        .execute(sqlite)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Replaces the token `id` by a new one with the same label and lifetime.
+/// Returns the id and the new token, or `None` if there is no such token.
+pub async fn rotate_token(sqlite: &SqlitePool, id: &str) -> sqlx::Result<Option<(String, String)>> {
+    let mut transaction = sqlite.begin().await?;
+    let Some(old) = sqlx::query!(
+        "SELECT hostname, label, created_at, expires_at FROM tokens WHERE id = ?;",
+        id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    else {
+        return Ok(None);
+    };
+    sqlx::query!("DELETE FROM tokens WHERE id = ?;", id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let lifetime = |expires_at: i64| expires_at.saturating_sub(old.created_at).max(0) as u64;
+    let expires_at = old
+        .expires_at
+        .map(|expires_at| now().saturating_add(lifetime(expires_at)));
+    let rotated = insert_token(&mut transaction, &old.hostname, &old.label, expires_at).await?;
+    transaction.commit().await?;
+
+    Ok(Some(rotated))
+}
+
+/// Stores an enrollment code for a client `hostname` of `permission_level`,
+/// removing the expired ones. Returns `false` if the hostname is taken.
+pub async fn add_enrollment(
+    sqlite: &SqlitePool,
+    code_hash: &str,
+    hostname: &str,
+    permission_level: &PermissionLevel,
+    expires_at: u64,
+) -> sqlx::Result<bool> {
+    let blob = bincode::serialize(permission_level).unwrap();
+    let (now, expires_at) = (now() as i64, expires_at as i64);
+
+    let mut transaction = sqlite.begin().await?;
+    sqlx::query!("DELETE FROM enrollments WHERE expires_at <= ?;", now)
+        .execute(&mut *transaction)
+        .await?;
+    let client = sqlx::query!("SELECT hostname FROM clients WHERE hostname = ?;", hostname)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    if client.is_some() {
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "INSERT INTO enrollments (code_hash, hostname, permission_level, expires_at)
+         VALUES (?, ?, ?, ?);",
+        code_hash,
+        hostname,
+        blob,
+        expires_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(true)
+}
+
+/// Exchanges an enrollment code for its client, which authenticates with
+/// the registered `public_key` or a generated token. Returns the hostname
+/// and the token, or `None` if the code is unknown, used up or expired, or
+/// the hostname has been taken since.
+pub async fn enroll(
+    sqlite: &SqlitePool,
+    code_hash: &str,
+    public_key: Option<&str>,
+) -> sqlx::Result<Option<(String, Option<String>)>> {
+    let now = now() as i64;
+
+    let mut transaction = sqlite.begin().await?;
+    let Some(enrollment) = sqlx::query!(
+        "SELECT hostname, permission_level FROM enrollments WHERE code_hash = ? AND expires_at > ?;",
+        code_hash,
+        now
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let client = sqlx::query!(
+        "SELECT hostname FROM clients WHERE hostname = ?;",
+        enrollment.hostname
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if client.is_some() {
+        return Ok(None);
+    }
+
+    sqlx::query!("DELETE FROM enrollments WHERE code_hash = ?;", code_hash)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO clients (hostname, permission_level, public_key) VALUES (?, ?, ?);",
+        enrollment.hostname,
+        enrollment.permission_level,
+        public_key
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let token = match public_key {
+        Some(_) => None,
+        None => {
+            let (_, token) =
+                insert_token(&mut transaction, &enrollment.hostname, "enrollment", None).await?;
+            Some(token)
+        }
+    };
+    transaction.commit().await?;
+
+    Ok(Some((enrollment.hostname, token)))
+}
+
+/// A client found by the id of one of its tokens.
+pub struct TokenOwner {
+    pub hostname: String,
+    pub permission_level: Vec<u8>,
+    /// Argon2 setting of the token secret, sent in challenges.
+    pub setting: String,
+    /// SHA-256 of the client key, see [`util::auth`].
+    pub stored_key: Option<Vec<u8>>,
+    /// Unix time in milliseconds.
+    pub expires_at: Option<i64>,
+}
+
+impl TokenOwner {
+    pub fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now() as i64)
+    }
+}
+
+/// Finds the client of the token `id`.
+pub async fn token_owner(sqlite: &SqlitePool, id: &str) -> sqlx::Result<Option<TokenOwner>> {
+    sqlx::query_as!(
+        TokenOwner,
+        "SELECT hostname, permission_level, setting, stored_key, expires_at
+         FROM tokens JOIN clients USING (hostname)
+         WHERE id = ?;",
+        id
+    )
+    .fetch_optional(sqlite)
+    .await
+}
+
+/// A client found by its registered public key.
+pub struct KeyOwner {
+    pub hostname: String,
+    pub permission_level: Vec<u8>,
+}
+
+/// Finds the client that registered `public_key`, a normalized PEM.
+pub async fn key_owner(sqlite: &SqlitePool, public_key: &str) -> sqlx::Result<Option<KeyOwner>> {
+    sqlx::query_as!(
+        KeyOwner,
+        "SELECT hostname, permission_level FROM clients WHERE public_key = ?;",
+        public_key
+    )
+    .fetch_optional(sqlite)
+    .await
+}
+
+/// Permission level of a client, or `None` if there is no such client.
+pub async fn permission_level(
+    sqlite: &SqlitePool,
+    hostname: &str,
+) -> sqlx::Result<Option<PermissionLevel>> {
+    let client = sqlx::query!(
+        "SELECT permission_level FROM clients WHERE hostname = ?;",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(client.and_then(|client| bincode::deserialize(&client.permission_level).ok()))
+}
+
+/// Registers or removes the public key of a client. Returns whether the
+/// client exists.
+pub async fn set_public_key(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    public_key: Option<&str>,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE clients SET public_key = ? WHERE hostname = ?;",
+        public_key,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// TOTP secrets of a client.
+pub struct TotpSecrets {
+    /// Secret whose codes admit admin commands.
+    pub active: Option<Vec<u8>>,
+    /// Secret of an enrollment awaiting its first code.
+    pub pending: Option<Vec<u8>>,
+}
+
+/// TOTP secrets of a client, or `None` if there is no such client.
+pub async fn totp_secrets(
+    sqlite: &SqlitePool,
+    hostname: &str,
+) -> sqlx::Result<Option<TotpSecrets>> {
+    let client = sqlx::query!(
+        "SELECT totp_secret, totp_pending FROM clients WHERE hostname = ?;",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(client.map(|client| TotpSecrets {
+        active: client.totp_secret,
+        pending: client.totp_pending,
+    }))
+}
+
+/// Stores a TOTP secret of a client awaiting confirmation. Returns whether
+/// the client exists.
+pub async fn set_pending_totp(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    secret: &[u8],
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE clients SET totp_pending = ? WHERE hostname = ?;",
+        secret,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Records that a code of the time step `step` was used. Returns `false` if
+/// a code of it or of a later step was used before, so codes are not
+/// accepted twice.
+pub async fn use_totp_step(sqlite: &SqlitePool, hostname: &str, step: u64) -> sqlx::Result<bool> {
+    let step = step as i64;
+    let result = sqlx::query!(
+        "UPDATE clients SET totp_last_step = ? WHERE hostname = ? AND totp_last_step < ?;",
+        step,
+        hostname,
+        step
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Makes the pending secret `secret` the active one, whose code of `step`
+/// confirmed it. Returns `false` if it is no longer pending.
+pub async fn activate_totp(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    secret: &[u8],
+    step: u64,
+) -> sqlx::Result<bool> {
+    let step = step as i64;
+    let result = sqlx::query!(
+        "UPDATE clients SET totp_secret = totp_pending, totp_pending = NULL, totp_last_step = ?
+         WHERE hostname = ? AND totp_pending = ?;",
+        step,
+        hostname,
+        secret
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes the TOTP secrets of a client. Returns whether the client exists.
+pub async fn disable_totp(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE clients SET totp_secret = NULL, totp_pending = NULL WHERE hostname = ?;",
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes a client with its tokens. Returns the ids of the removed tokens,
+/// or `None` if there is no such client.
+pub async fn remove_client(
+    sqlite: &SqlitePool,
+    username: &str,
+) -> sqlx::Result<Option<Vec<String>>> {
+    let mut transaction = sqlite.begin().await?;
+    let tokens = sqlx::query!(
+        r#"DELETE FROM tokens WHERE hostname = ? RETURNING id AS "id!";"#,
+        username
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let result = sqlx::query!("DELETE FROM clients WHERE hostname = ?;", username)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    Ok((result.rows_affected() > 0).then(|| tokens.into_iter().map(|token| token.id).collect()))
+}
+
+/// Lists at most `limit` clients whose hostnames come after `after`.
+pub async fn list_clients(
+    sqlite: &SqlitePool,
+    after: &str,
+    limit: u64,
+) -> sqlx::Result<Vec<ClientInfo>> {
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    let clients = sqlx::query!(
+        r#"SELECT hostname, permission_level, rate_up, rate_down, session_rate_up, session_rate_down,
+                max_forwards, max_requests_per_minute, max_transfer, public_key, allowed_sources,
+                totp_secret IS NOT NULL AS "totp!: bool",
+                COALESCE((SELECT bytes FROM traffic
+                          WHERE traffic.hostname = clients.hostname
+                          AND period = strftime('%Y-%m', 'now')), 0) AS "transfer!: i64"
+         FROM clients WHERE hostname > ? ORDER BY hostname LIMIT ?;"#,
+        after,
+        limit
+    )
+    .fetch_all(sqlite)
+    .await?;
+
+    Ok(clients
+        .into_iter()
+        .filter_map(|client| {
+            Some(ClientInfo {
+                hostname: client.hostname,
+                permission_level: bincode::deserialize(&client.permission_level).ok()?,
+                rate_limits: RateLimits {
+                    up: client.rate_up.map(|rate| rate as u64),
+                    down: client.rate_down.map(|rate| rate as u64),
+                    session_up: client.session_rate_up.map(|rate| rate as u64),
+                    session_down: client.session_rate_down.map(|rate| rate as u64),
+                },
+                quotas: Quotas {
+                    forwards: client.max_forwards.map(|max| max as u64),
+                    requests_per_minute: client.max_requests_per_minute.map(|max| max as u64),
+                    transfer: client.max_transfer.map(|max| max as u64),
+                },
+                usage: QuotaUsage {
+                    transfer: client.transfer as u64,
+                    ..QuotaUsage::default()
+                },
+                key_fingerprint: client
+                    .public_key
+                    .as_deref()
+                    .and_then(util::auth::fingerprint),
+                totp: client.totp,
+                allowed_sources: client.allowed_sources.as_deref().map(sources::split),
+            })
+        })
+        .collect())
+}
+
+/// Restricts the addresses the client `hostname` may authenticate from to
+/// `sources` as stored by [`sources::normalize`], or lifts the restriction.
+/// Returns whether the client exists.
+pub async fn set_allowed_sources(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    sources: Option<&str>,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE clients SET allowed_sources = ? WHERE hostname = ?;",
+        sources,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Stored CIDR ranges the client `hostname` may authenticate from, `None`
+/// if it is not restricted or not in the database.
+pub async fn allowed_sources(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<Option<String>> {
+    let client = sqlx::query!(
+        "SELECT allowed_sources FROM clients WHERE hostname = ?;",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(client.and_then(|client| client.allowed_sources))
+}
+
+/// Bandwidth limits of a client, or `None` if there is no such client.
+pub async fn rate_limits(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<Option<RateLimits>> {
+    let limits = sqlx::query!(
+        "SELECT rate_up, rate_down, session_rate_up, session_rate_down FROM clients WHERE hostname = ?;",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(limits.map(|limits| RateLimits {
+        up: limits.rate_up.map(|rate| rate as u64),
+        down: limits.rate_down.map(|rate| rate as u64),
+        session_up: limits.session_rate_up.map(|rate| rate as u64),
+        session_down: limits.session_rate_down.map(|rate| rate as u64),
+    }))
+}
+
+/// Replaces the bandwidth limits of a client. Returns whether the client
+/// exists.
+pub async fn set_rate_limits(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    limits: &RateLimits,
+) -> sqlx::Result<bool> {
+    let rate = |rate: Option<u64>| rate.map(|rate| i64::try_from(rate).unwrap_or(i64::MAX));
+    let (up, down) = (rate(limits.up), rate(limits.down));
+    let (session_up, session_down) = (rate(limits.session_up), rate(limits.session_down));
+
+    let result = sqlx::query!(
+        "UPDATE clients SET rate_up = ?, rate_down = ?, session_rate_up = ?, session_rate_down = ?
+         WHERE hostname = ?;",
+        up,
+        down,
+        session_up,
+        session_down,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Quotas of a client, or `None` if there is no such client.
+pub async fn quotas(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<Option<Quotas>> {
+    let quotas = sqlx::query!(
+        "SELECT max_forwards, max_requests_per_minute, max_transfer FROM clients WHERE hostname = ?;",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(quotas.map(|quotas| Quotas {
+        forwards: quotas.max_forwards.map(|max| max as u64),
+        requests_per_minute: quotas.max_requests_per_minute.map(|max| max as u64),
+        transfer: quotas.max_transfer.map(|max| max as u64),
+    }))
+}
+
+/// Replaces the quotas of a client. The thresholds of the transfer quota are
+/// warned about again. Returns whether the client exists.
+pub async fn set_quotas(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    quotas: &Quotas,
+) -> sqlx::Result<bool> {
+    let max = |max: Option<u64>| max.map(|max| i64::try_from(max).unwrap_or(i64::MAX));
+    let (forwards, requests_per_minute) = (max(quotas.forwards), max(quotas.requests_per_minute));
+    let transfer = max(quotas.transfer);
+
+    let result = sqlx::query!(
+        "UPDATE clients SET max_forwards = ?, max_requests_per_minute = ?, max_transfer = ?
+         WHERE hostname = ?;",
+        forwards,
+        requests_per_minute,
+        transfer,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE traffic SET warned = 0 WHERE hostname = ? AND period = strftime('%Y-%m', 'now');",
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Transfer quota usage of a client in the current period.
+pub struct Traffic {
+    pub bytes: u64,
+    pub quota: Option<u64>,
+    /// Highest threshold in percent that has been warned about.
+    pub warned: u8,
+}
+
+/// Adds `bytes` to the transfer quota usage of a client in the current
+/// period. Returns the new usage, or `None` if there is no such client.
+pub async fn charge_traffic(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    bytes: u64,
+) -> sqlx::Result<Option<Traffic>> {
+    let bytes = i64::try_from(bytes).unwrap_or(i64::MAX);
+
+    sqlx::query!(
+        "INSERT INTO traffic (hostname, period, bytes)
+         SELECT hostname, strftime('%Y-%m', 'now'), ? FROM clients WHERE hostname = ?
+         ON CONFLICT (hostname, period) DO UPDATE SET bytes = bytes + excluded.bytes;",
+        bytes,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    let traffic = sqlx::query!(
+        "SELECT bytes, warned, max_transfer FROM traffic JOIN clients USING (hostname)
+         WHERE hostname = ? AND period = strftime('%Y-%m', 'now');",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(traffic.map(|traffic| Traffic {
+        bytes: traffic.bytes as u64,
+        quota: traffic.max_transfer.map(|max| max as u64),
+        warned: traffic.warned as u8,
+    }))
+}
+
+/// Records that the transfer quota usage of a client has been warned about
+/// up to `threshold` percent in the current period.
+pub async fn set_traffic_warned(
+    sqlite: &SqlitePool,
+    hostname: &str,
+    threshold: u8,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE traffic SET warned = ? WHERE hostname = ? AND period = strftime('%Y-%m', 'now');",
+        threshold,
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(())
+}
+
+/// Whether a client has used up its transfer quota in the current period.
+pub async fn traffic_exhausted(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<bool> {
+    let exhausted = sqlx::query!(
+        "SELECT bytes FROM traffic JOIN clients USING (hostname)
+         WHERE hostname = ? AND period = strftime('%Y-%m', 'now') AND bytes >= max_transfer;",
+        hostname
+    )
+    .fetch_optional(sqlite)
+    .await?;
+
+    Ok(exhausted.is_some())
+}
+
+/// Lists the transfer quota usage of the latest `limit` periods, only the
+/// usage of `hostname` if given.
+pub async fn list_traffic(
+    sqlite: &SqlitePool,
+    hostname: Option<&str>,
+    limit: u64,
+) -> sqlx::Result<Vec<TrafficUsage>> {
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    let usage = sqlx::query!(
+        "SELECT hostname, period, bytes, max_transfer FROM traffic JOIN clients USING (hostname)
+         WHERE ?1 IS NULL OR hostname = ?1
+         ORDER BY period DESC, hostname LIMIT ?2;",
+        hostname,
+        limit
+    )
+    .fetch_all(sqlite)
+    .await?;
+
+    Ok(usage
+        .into_iter()
+        .map(|usage| TrafficUsage {
+            hostname: usage.hostname,
+            period: usage.period,
+            bytes: usage.bytes as u64,
+            quota: usage.max_transfer.map(|max| max as u64),
+        })
+        .collect())
+}
+
+/// Resets the transfer quota usage of a client in the current period.
+pub async fn reset_traffic(sqlite: &SqlitePool, hostname: &str) -> sqlx::Result<()> {
+    sqlx::query!(
+        "DELETE FROM traffic WHERE hostname = ? AND period = strftime('%Y-%m', 'now');",
+        hostname
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(())
+}
+
+/// Records a finished forwarding session.
+pub async fn record_session(sqlite: &SqlitePool, session: &SessionInfo) -> sqlx::Result<()> {
+    let id = session.id as i64;
+    let started_at = session.started_at as i64;
+    let ended_at = session.ended_at as i64;
+    let bytes_up = session.bytes_up as i64;
+    let bytes_down = session.bytes_down as i64;
+
+    sqlx::query!(
+        "INSERT INTO sessions (share_id, requester, node, port, started_at, ended_at, bytes_up, bytes_down, close_reason)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);",
+        id,
+        session.requester,
+        session.node,
+        session.port,
+        started_at,
+        ended_at,
+        bytes_up,
+        bytes_down,
+        session.close_reason
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(())
+}
+
+/// Lists the latest `limit` sessions, only the ones of `hostname` if given.
+pub async fn list_sessions(
+    sqlite: &SqlitePool,
+    hostname: Option<&str>,
+    limit: u64,
+) -> sqlx::Result<Vec<SessionInfo>> {
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    let sessions = sqlx::query!(
+        "SELECT share_id, requester, node, port, started_at, ended_at, bytes_up, bytes_down, close_reason
+         FROM sessions WHERE ?1 IS NULL OR requester = ?1 OR node = ?1
+         ORDER BY id DESC LIMIT ?2;",
+        hostname,
+        limit
+    )
+    .fetch_all(sqlite)
+    .await?;
+
+    Ok(sessions
+        .into_iter()
+        .map(|session| SessionInfo {
+            id: session.share_id as u64,
+            requester: session.requester,
+            node: session.node,
+            port: session.port as u32,
+            started_at: session.started_at as u64,
+            ended_at: session.ended_at as u64,
+            bytes_up: session.bytes_up as u64,
+            bytes_down: session.bytes_down as u64,
+            close_reason: session.close_reason,
+        })
+        .collect())
+}
+
+/// Appends an entry to the audit log.
+pub async fn add_audit_entry(sqlite: &SqlitePool, entry: &AuditEntry) -> sqlx::Result<()> {
+    let timestamp = entry.timestamp as i64;
+
+    sqlx::query!(
+        "INSERT INTO audit_log (timestamp, actor, peer, action, target, outcome)
+         VALUES (?, ?, ?, ?, ?, ?);",
+        timestamp,
+        entry.actor,
+        entry.peer,
+        entry.action,
+        entry.target,
+        entry.outcome
+    )
+    .execute(sqlite)
+    .await?;
+
+    Ok(())
+}
+
+/// Lists the latest `limit` audit log entries matching `filter`.
+pub async fn list_audit(
+    sqlite: &SqlitePool,
+    filter: &AuditFilter,
+    limit: u64,
+) -> sqlx::Result<Vec<AuditEntry>> {
+    let since = filter.since.map(|since| since as i64);
+    let until = filter.until.map(|until| until as i64);
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+
+    let entries = sqlx::query!(
+        "SELECT timestamp, actor, peer, action, target, outcome FROM audit_log
+         WHERE (?1 IS NULL OR actor = ?1)
+           AND (?2 IS NULL OR peer = ?2)
+           AND (?3 IS NULL OR action = ?3)
+           AND (?4 IS NULL OR target = ?4)
+           AND (?5 IS NULL OR timestamp >= ?5)
+           AND (?6 IS NULL OR timestamp < ?6)
+         ORDER BY id DESC LIMIT ?7;",
+        filter.actor,
+        filter.peer,
+        filter.action,
+        filter.target,
+        since,
+        until,
+        limit
+    )
+    .fetch_all(sqlite)
+    .await?;
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| AuditEntry {
+            timestamp: entry.timestamp as u64,
+            actor: entry.actor,
+            peer: entry.peer,
+            action: entry.action,
+            target: entry.target,
+            outcome: entry.outcome,
+        })
+        .collect())
+}
+
+/// Current unix time in milliseconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, actor: &str, action: &str, target: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            timestamp,
+            actor: Some(actor.to_string()),
+            peer: "127.0.0.1:1234".to_string(),
+            action: action.to_string(),
+            target: target.map(str::to_string),
+            outcome: "ok".to_string(),
+        }
+    }
+
+    async fn audit_log() -> SqlitePool {
+        let sqlite = connect("sqlite::memory:").await;
+        for entry in [
+            entry(1000, "admin", "add_client", Some("node")),
+            entry(2000, "admin", "remove_client", Some("node")),
+            entry(3000, "node", "get_port", Some("other:22")),
+            entry(4000, "admin", "add_client", Some("other")),
+        ] {
+            add_audit_entry(&sqlite, &entry).await.unwrap();
+        }
+
+        sqlite
+    }
+
+    fn timestamps(entries: &[AuditEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.timestamp).collect()
+    }
+
+    #[tokio::test]
+    async fn audit_log_lists_latest_entries_first() {
+        let sqlite = audit_log().await;
+
+        let entries = list_audit(&sqlite, &AuditFilter::default(), u64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(timestamps(&entries), [4000, 3000, 2000, 1000]);
+
+        let entries = list_audit(&sqlite, &AuditFilter::default(), 2)
+            .await
+            .unwrap();
+        assert_eq!(timestamps(&entries), [4000, 3000]);
+    }
+
+    #[tokio::test]
+    async fn audit_log_filters_combine() {
+        let sqlite = audit_log().await;
+
+        let filter = AuditFilter {
+            actor: Some("admin".to_string()),
+            target: Some("node".to_string()),
+            ..AuditFilter::default()
+        };
+        let entries = list_audit(&sqlite, &filter, u64::MAX).await.unwrap();
+        assert_eq!(timestamps(&entries), [2000, 1000]);
+
+        let filter = AuditFilter {
+            action: Some("add_client".to_string()),
+            since: Some(1000),
+            until: Some(4000),
+            ..AuditFilter::default()
+        };
+        let entries = list_audit(&sqlite, &filter, u64::MAX).await.unwrap();
+        assert_eq!(timestamps(&entries), [1000]);
+
+        let filter = AuditFilter {
+            peer: Some("10.0.0.1:1234".to_string()),
+            ..AuditFilter::default()
+        };
+        assert!(list_audit(&sqlite, &filter, u64::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use crate::config::JwtConfig;
+use jsonwebtoken::{
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::{Map, Value};
+use std::{fs, str::FromStr};
+use util::PermissionLevel;
+
+/// Verifies JWTs with the keys of a local JWKS file and maps their claims
+/// to a client, so the identity provider is never contacted.
+pub struct Verifier {
+    keys: Vec<Key>,
+    hostname_claim: String,
+    groups_claim: String,
+    /// Permission levels of the groups, the first one the token has applies.
+    groups: Vec<(String, PermissionLevel)>,
+    /// Level of tokens without a mapped group, which are refused if unset.
+    permission_level: Option<PermissionLevel>,
+}
+
+/// A key of the JWKS with the validation of the tokens it signs.
+struct Key {
+    id: Option<String>,
+    key: DecodingKey,
+    validation: Validation,
+}
+
+/// Client a verified JWT authenticates as.
+pub struct Bearer {
+    pub hostname: String,
+    pub permission_level: PermissionLevel,
+    /// `exp` claim in unix milliseconds.
+    pub expires_at: u64,
+}
+
+impl Verifier {
+    /// Reads the JWKS file and the claim mapping of `config`.
+    pub fn new(config: &JwtConfig) -> Result<Self, String> {
+        let jwks = fs::read_to_string(&config.jwks)
+            .map_err(|error| format!("cannot read JWKS {}: {error}", config.jwks))?;
+        let jwks: JwkSet =
+            serde_json::from_str(&jwks).map_err(|error| format!("invalid JWKS: {error}"))?;
+        if jwks.keys.is_empty() {
+            return Err(String::from("JWKS has no keys"));
+        }
+
+        let keys = jwks
+            .keys
+            .iter()
+            .map(|jwk| Key::new(jwk, config))
+            .collect::<Result<_, _>>()?;
+        let groups = config
+            .groups
+            .iter()
+            .map(|group| {
+                let level = group.permission_level.parse()?;
+                Ok((group.name.clone(), level))
+            })
+            .collect::<Result<_, String>>()?;
+        let permission_level = config
+            .permission_level
+            .as_deref()
+            .map(str::parse)
+            .transpose()?;
+
+        Ok(Self {
+            keys,
+            hostname_claim: config.hostname_claim.clone(),
+            groups_claim: config.groups_claim.clone(),
+            groups,
+            permission_level,
+        })
+    }
+
+    /// Checks the signature, issuer, audience and lifetime of `jwt` and maps
+    /// its claims to a client.
+    pub fn verify(&self, jwt: &str) -> Result<Bearer, &'static str> {
+        let header = jsonwebtoken::decode_header(jwt).map_err(|_| "malformed JWT")?;
+        let key = match &header.kid {
+            Some(kid) => self.keys.iter().find(|key| key.id.as_ref() == Some(kid)),
+            // tokens without a key id are only accepted from a single key
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+        .ok_or("unknown JWT key")?;
+
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(jwt, &key.key, &key.validation)
+            .map_err(|error| match error.kind() {
+                ErrorKind::ExpiredSignature => "JWT expired",
+                ErrorKind::ImmatureSignature => "JWT not yet valid",
+                ErrorKind::InvalidIssuer | ErrorKind::InvalidAudience => {
+                    "JWT not issued for this proxy"
+                }
+                _ => "invalid JWT",
+            })?
+            .claims;
+
+        let hostname = claims
+            .get(&self.hostname_claim)
+            .and_then(Value::as_str)
+            .filter(|hostname| !hostname.is_empty())
+            .ok_or("JWT has no hostname claim")?;
+        let groups = match claims.get(&self.groups_claim) {
+            Some(Value::Array(groups)) => groups.iter().filter_map(Value::as_str).collect(),
+            Some(Value::String(group)) => vec![group.as_str()],
+            _ => Vec::new(),
+        };
+        let permission_level = self
+            .groups
+            .iter()
+            .find(|(group, _)| groups.contains(&group.as_str()))
+            .map(|(_, level)| level.clone())
+            .or_else(|| self.permission_level.clone())
+            .ok_or("JWT has no permitted group")?;
+        let expires_at = claims
+            .get("exp")
+            .and_then(Value::as_f64)
+            .ok_or("invalid JWT")?;
+
+        Ok(Bearer {
+            hostname: hostname.to_owned(),
+            permission_level,
+            expires_at: (expires_at * 1000.0) as u64,
+        })
+    }
+}
+
+impl Key {
+    fn new(jwk: &Jwk, config: &JwtConfig) -> Result<Self, String> {
+        let family = match &jwk.algorithm {
+            // shorter keys fail every verification
+            AlgorithmParameters::RSA(rsa) if rsa.n.trim_end_matches('=').len() * 3 / 4 < 256 => {
+                return Err(String::from("JWKS has an RSA key shorter than 2048 bits"));
+            }
+            AlgorithmParameters::RSA(_) => vec![
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+            AlgorithmParameters::EllipticCurve(_) => vec![Algorithm::ES256, Algorithm::ES384],
+            AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+            // a shared secret would let anyone who reads the file mint tokens
+            AlgorithmParameters::OctetKey(_) => {
+                return Err(String::from("JWKS has a symmetric key"));
+            }
+        };
+        let algorithms = match &jwk.common.key_algorithm {
+            Some(algorithm) => match Algorithm::from_str(&algorithm.to_string()) {
+                Ok(algorithm) if family.contains(&algorithm) => vec![algorithm],
+                _ => return Err(format!("JWKS key has an unusable algorithm {algorithm}")),
+            },
+            None => family,
+        };
+
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&config.issuer]);
+        validation.set_audience(&[&config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.validate_nbf = true;
+        validation.leeway = config.leeway;
+
+        Ok(Self {
+            id: jwk.common.key_id.clone(),
+            key: DecodingKey::from_jwk(jwk)
+                .map_err(|error| format!("invalid JWKS key: {error}"))?,
+            validation,
+        })
+    }
+}
@@ -1,10 +1,28 @@
 //! Port forwarding server for controlling mass-servers. Handles command and
 //! TCP forwarding connecitons.
 
+/// Layered configuration of the binary.
+pub mod config;
+
 /// Common conneciton utils to handle port forwardings.
 pub mod connection;
 
+/// Database queries shared by the server and the command line.
+pub mod database;
+
+/// Verification of JWT bearer tokens of an identity provider.
+pub mod jwt;
+
+/// CIDR allowlists of the addresses clients may authenticate from.
+pub mod sources;
+
 /// Port forwarding server.
 pub mod server;
 
+/// Generation and verification of the client tokens and enrollment codes.
+pub mod token;
+
+/// Time-based one-time passwords of the admin second factor.
+pub mod totp;
+
 pub use server::{Server, ServerBuilder};
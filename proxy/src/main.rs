@@ -1,16 +1,313 @@
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-use proxy::ServerBuilder;
+use proxy::{config::Config, database, token, ServerBuilder};
+use std::{process::ExitCode, sync::Arc, time::Duration};
+use util::PermissionLevel;
+
+#[derive(Parser)]
+#[command(about = "Proxy for TCP port forwarding")]
+struct Cli {
+    /// TOML configuration file, overridden by environment and flags
+    #[arg(long, env = "PROXY_CONFIG", global = true)]
+    config: Option<String>,
+    /// sqlite database url [env: DATABASE_URL]
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serves the proxy server (default).
+    Serve,
+    /// Manages clients in the database.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Reports every problem of the configuration.
+    CheckConfig,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// PKCS#1 PEM private key of the server [env: CERT]
+    #[arg(long, global = true)]
+    cert: Option<String>,
+    /// address of the command and forwarding listener [env: HOST]
+    #[arg(long, global = true)]
+    host: Option<String>,
+    /// address of the HTTP virtual-host listener [env: HTTP_HOST]
+    #[arg(long, global = true)]
+    http_host: Option<String>,
+    /// address of the TLS SNI listener [env: TLS_HOST]
+    #[arg(long, global = true)]
+    tls_host: Option<String>,
+    /// address of the Prometheus metrics listener [env: METRICS_HOST]
+    #[arg(long, global = true)]
+    metrics_host: Option<String>,
+    /// JSONL file the audit log is also appended to [env: AUDIT_FILE]
+    #[arg(long, global = true)]
+    audit_file: Option<String>,
+    /// comma separated percentages of the transfer quotas to warn at
+    /// [env: TRAFFIC_WARNINGS]
+    #[arg(long, global = true, value_delimiter = ',')]
+    traffic_warnings: Option<Vec<u8>>,
+    /// terminate live sessions once a transfer quota is used up
+    /// [env: TERMINATE_OVER_QUOTA]
+    #[arg(long, global = true)]
+    terminate_over_quota: bool,
+    /// seconds a TOTP step-up admits admin commands [env: TOTP_GRACE]
+    #[arg(long, global = true)]
+    totp_grace: Option<u32>,
+    /// seconds a connection has to authenticate [env: AUTH_TIMEOUT]
+    #[arg(long, global = true)]
+    auth_timeout: Option<u32>,
+    /// connections that may be unauthenticated at the same time
+    /// [env: MAX_UNAUTHENTICATED]
+    #[arg(long, global = true)]
+    max_unauthenticated: Option<u32>,
+    /// log filter, e.g. info or proxy=debug [env: LOG_LEVEL]
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// human or json [env: LOG_FORMAT]
+    #[arg(long, global = true)]
+    log_format: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Adds a client and prints its generated token, unless it authenticates
+    /// with a public key.
+    Add {
+        username: String,
+        /// standard, node or admin:<level>
+        #[arg(long, default_value = "standard")]
+        permission_level: PermissionLevel,
+        /// PKCS#1 PEM public key file the client authenticates with instead
+        /// of a token
+        #[arg(long)]
+        public_key: Option<String>,
+    },
+    /// Prints a single-use code with which the client enrolls itself.
+    Enroll {
+        username: String,
+        /// standard, node or admin:<level>
+        #[arg(long, default_value = "standard")]
+        permission_level: PermissionLevel,
+        /// seconds the code is valid, at most 30 days
+        #[arg(long, default_value_t = 3600)]
+        valid_for: u64,
+    },
+    /// Removes a client.
+    Remove { username: String },
+    /// Registers the PKCS#1 PEM public key file of a client, or removes its
+    /// key if the file is omitted.
+    Key {
+        username: String,
+        public_key: Option<String>,
+    },
+    /// Restricts the addresses a client may authenticate from to CIDR
+    /// ranges, or lifts the restriction if none are given.
+    Sources {
+        username: String,
+        sources: Vec<String>,
+    },
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenv().ok();
-    util::env![CERT, DATABASE_URL, HOST];
-
-    ServerBuilder::new()
-        .private_key_file(*CERT)
-        .sqlite_database(*DATABASE_URL)
-        .await
-        .build()
-        .serve(*HOST)
-        .await;
+    let cli = Cli::parse();
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    let loader = Config::layers(cli.config.as_deref())
+        .flag("database_url", cli.database_url)
+        .flag("cert", cli.serve.cert)
+        .flag("host", cli.serve.host)
+        .flag("http_host", cli.serve.http_host)
+        .flag("tls_host", cli.serve.tls_host)
+        .flag("metrics_host", cli.serve.metrics_host)
+        .flag("audit_file", cli.serve.audit_file)
+        .flag("traffic_warnings", cli.serve.traffic_warnings)
+        .flag(
+            "terminate_over_quota",
+            cli.serve.terminate_over_quota.then_some(true),
+        )
+        .flag("totp_grace", cli.serve.totp_grace)
+        .flag("auth_timeout", cli.serve.auth_timeout)
+        .flag("max_unauthenticated", cli.serve.max_unauthenticated)
+        .flag("log_level", cli.serve.log_level)
+        .flag("log_format", cli.serve.log_format);
+
+    let serve = matches!(command, Command::Serve | Command::CheckConfig);
+    let config = match Config::load(loader, serve) {
+        Ok(config) => config,
+        Err(problems) => {
+            eprintln!("{problems}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match command {
+        Command::Serve => {
+            let (Some(cert), Some(host)) = (config.cert, config.host) else {
+                unreachable!("required to serve");
+            };
+            config.logging.init();
+
+            let mut builder = ServerBuilder::new()
+                .private_key_file(&cert)
+                .traffic_warnings(config.traffic_warnings)
+                .terminate_over_quota(config.terminate_over_quota)
+                .totp_grace(Duration::from_secs(config.totp_grace))
+                .auth_timeout(Duration::from_secs(config.auth_timeout))
+                .max_unauthenticated(config.max_unauthenticated);
+            if let Some(audit_file) = &config.audit_file {
+                builder = builder.audit_file(audit_file);
+            }
+            if let Some(jwt) = &config.jwt {
+                builder = builder.jwt(jwt);
+            }
+            let server = builder.sqlite_database(&config.database_url).await.build();
+
+            if let Some(http_host) = config.http_host {
+                tokio::spawn(Arc::clone(&server).serve_http(http_host));
+            }
+
+            if let Some(tls_host) = config.tls_host {
+                tokio::spawn(Arc::clone(&server).serve_tls(tls_host));
+            }
+
+            if let Some(metrics_host) = config.metrics_host {
+                tokio::spawn(Arc::clone(&server).serve_metrics(metrics_host));
+            }
+
+            server.serve(host).await;
+        }
+        Command::CheckConfig => {
+            println!("configuration is valid");
+            ExitCode::SUCCESS
+        }
+        Command::User(command) => {
+            let sqlite = database::connect(&config.database_url).await;
+
+            let result = match command {
+                UserCommand::Add {
+                    username,
+                    permission_level,
+                    public_key,
+                } => match read_public_key(public_key.as_deref()) {
+                    Ok(public_key) => match database::add_client(
+                        &sqlite,
+                        &username,
+                        &permission_level,
+                        public_key.as_deref(),
+                    )
+                    .await
+                    {
+                        Ok(Some(token)) => {
+                            if let Some(token) = token {
+                                println!("{token}");
+                            }
+                            Ok(())
+                        }
+                        Ok(None) => Err(String::from("client exists")),
+                        Err(error) => Err(error.to_string()),
+                    },
+                    Err(error) => Err(error),
+                },
+                UserCommand::Enroll {
+                    username,
+                    permission_level,
+                    valid_for,
+                } => match token::enrollment_expiry(valid_for) {
+                    Some(expires_at) => {
+                        let (code, code_hash) = token::enrollment_code();
+                        match database::add_enrollment(
+                            &sqlite,
+                            &code_hash,
+                            &username,
+                            &permission_level,
+                            expires_at,
+                        )
+                        .await
+                        {
+                            Ok(true) => {
+                                println!("{code}");
+                                Ok(())
+                            }
+                            Ok(false) => Err(String::from("client exists")),
+                            Err(error) => Err(error.to_string()),
+                        }
+                    }
+                    None => Err(format!(
+                        "--valid-for must be between 1 and {} seconds",
+                        token::MAX_ENROLLMENT_VALIDITY
+                    )),
+                },
+                UserCommand::Remove { username } => {
+                    match database::remove_client(&sqlite, &username).await {
+                        Ok(Some(_)) => Ok(()),
+                        Ok(None) => Err(String::from("no such client")),
+                        Err(error) => Err(error.to_string()),
+                    }
+                }
+                UserCommand::Key {
+                    username,
+                    public_key,
+                } => match read_public_key(public_key.as_deref()) {
+                    Ok(public_key) => {
+                        match database::set_public_key(&sqlite, &username, public_key.as_deref())
+                            .await
+                        {
+                            Ok(true) => Ok(()),
+                            Ok(false) => Err(String::from("no such client")),
+                            Err(error) => Err(error.to_string()),
+                        }
+                    }
+                    Err(error) => Err(error),
+                },
+                // without ranges the restriction is lifted
+                UserCommand::Sources { username, sources } => match (!sources.is_empty())
+                    .then(|| proxy::sources::normalize(&sources))
+                    .transpose()
+                {
+                    Ok(sources) => {
+                        match database::set_allowed_sources(&sqlite, &username, sources.as_deref())
+                            .await
+                        {
+                            Ok(true) => Ok(()),
+                            Ok(false) => Err(String::from("no such client")),
+                            Err(error) => Err(error.to_string()),
+                        }
+                    }
+                    Err(error) => Err(error),
+                },
+            };
+
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(error) => {
+                    eprintln!("{error}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
+
+/// Reads and normalizes a PKCS#1 PEM public key file.
+fn read_public_key(path: Option<&str>) -> Result<Option<String>, String> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let pem = std::fs::read_to_string(path).map_err(|error| format!("{path}: {error}"))?;
+
+    util::auth::normalize_public_key(&pem)
+        .map(Some)
+        .ok_or_else(|| format!("{path}: not a PKCS#1 PEM public key"))
 }
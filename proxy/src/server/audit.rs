@@ -0,0 +1,79 @@
+use crate::database;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use util::{AuditEntry, Cmd};
+
+impl super::Server {
+    /// Appends `entry` to the audit log and the JSONL sink, if configured.
+    pub(crate) async fn audit(&self, entry: AuditEntry) {
+        if let Err(error) = database::add_audit_entry(&self.sqlite, &entry).await {
+            warn!(%error, "cannot write audit log");
+        }
+
+        if let Some(sink) = &self.audit_sink {
+            let Ok(mut line) = serde_json::to_string(&entry) else {
+                return;
+            };
+            line.push('\n');
+
+            if let Err(error) = sink.lock().await.write_all(line.as_bytes()).await {
+                warn!(%error, "cannot write audit file");
+            }
+        }
+    }
+}
+
+/// Action and target of an audited command. Keepalives, share answers and
+/// responses are not audited.
+pub(crate) fn describe(cmd: &Cmd) -> Option<(&'static str, Option<String>)> {
+    Some(match cmd {
+        Cmd::Authenticate { .. } | Cmd::Bearer { .. } => ("authenticate", None),
+        Cmd::Redeem { .. } => ("redeem_ticket", None),
+        Cmd::Enroll { .. } => ("enroll", None),
+        Cmd::CreateEnrollment { hostname, .. } => ("create_enrollment", Some(hostname.clone())),
+        Cmd::GetPort { hostname, port, .. } => ("get_port", Some(format!("{hostname}:{port}"))),
+        Cmd::ListClients { .. } => ("list_clients", None),
+        Cmd::AddClient { username, .. } => ("add_client", Some(username.clone())),
+        Cmd::RemoveClient { username } => ("remove_client", Some(username.clone())),
+        Cmd::SetPublicKey { username, .. } => ("set_public_key", Some(username.clone())),
+        Cmd::AddToken { username, .. } => ("add_token", Some(username.clone())),
+        Cmd::ListTokens { username } => ("list_tokens", Some(username.clone())),
+        Cmd::RevokeToken { id } => ("revoke_token", Some(id.clone())),
+        Cmd::RotateToken => ("rotate_token", None),
+        Cmd::StepUp { .. } => ("step_up", None),
+        Cmd::EnrollTotp => ("enroll_totp", None),
+        Cmd::ConfirmTotp { .. } => ("confirm_totp", None),
+        Cmd::DisableTotp { username } => ("disable_totp", Some(username.clone())),
+        Cmd::SetAllowedSources { username, .. } => ("set_allowed_sources", Some(username.clone())),
+        Cmd::SetRateLimits { username, .. } => ("set_rate_limits", Some(username.clone())),
+        Cmd::SetQuotas { username, .. } => ("set_quotas", Some(username.clone())),
+        Cmd::AddHttpRoute { domain, .. } => ("add_http_route", Some(domain.clone())),
+        Cmd::RemoveHttpRoute { domain } => ("remove_http_route", Some(domain.clone())),
+        Cmd::AddTlsRoute { server_name, .. } => ("add_tls_route", Some(server_name.clone())),
+        Cmd::RemoveTlsRoute { server_name } => ("remove_tls_route", Some(server_name.clone())),
+        Cmd::ListSessions { hostname, .. } => ("list_sessions", hostname.clone()),
+        Cmd::ListTraffic { hostname, .. } => ("list_traffic", hostname.clone()),
+        Cmd::ResetTraffic { username } => ("reset_traffic", Some(username.clone())),
+        Cmd::ListAudit { .. } => ("list_audit", None),
+        Cmd::Noop
+        | Cmd::SharePort { .. }
+        | Cmd::Ok
+        | Cmd::Error { .. }
+        | Cmd::QuotaExceeded { .. }
+        | Cmd::Hello { .. }
+        | Cmd::KeyHello { .. }
+        | Cmd::IssueTicket { .. }
+        | Cmd::Ticket { .. }
+        | Cmd::Tokens { .. }
+        | Cmd::TotpSecret { .. }
+        | Cmd::EnrollmentCode { .. }
+        | Cmd::Enrolled { .. }
+        | Cmd::Challenge { .. }
+        | Cmd::AuthenticationFailed { .. }
+        | Cmd::Token { .. }
+        | Cmd::Clients { .. }
+        | Cmd::Sessions { .. }
+        | Cmd::Traffic { .. }
+        | Cmd::Audit { .. } => return None,
+    })
+}
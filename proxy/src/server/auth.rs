@@ -0,0 +1,407 @@
+use crate::{
+    connection::{ConnectionState, Credential},
+    database, sources, token, totp,
+};
+use ptls::Ptls;
+use rand::Rng;
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    sync::{broadcast::error::RecvError, watch},
+};
+use tracing::{info, warn, Span};
+use util::{auth, AuditEntry, Cmd, PermissionLevel, Secret};
+
+/// Challenges answered later than this are refused.
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl super::Server {
+    /// Handles the commands of a connection that has not authenticated yet.
+    pub(crate) async fn authenticate(
+        &self,
+        cmd: Cmd,
+        connection_state: &mut ConnectionState,
+        server_ptls: &Arc<Ptls<OwnedReadHalf, OwnedWriteHalf>>,
+        peer: SocketAddr,
+    ) -> Cmd {
+        match cmd {
+            Cmd::Hello { token_id } => {
+                let setting = match database::token_owner(&self.sqlite, &token_id).await {
+                    Ok(Some(owner)) => owner.setting,
+                    // unknown ids are challenged like known ones
+                    Ok(None) => token::dummy_setting(&token_id, &self.salt_key),
+                    Err(_) => return Cmd::error("cannot read token"),
+                };
+
+                challenge(connection_state, Credential::Token(token_id), setting)
+            }
+            Cmd::KeyHello { public_key } => {
+                // unregistered keys are challenged like registered ones
+                let Some(public_key) = auth::normalize_public_key(&public_key) else {
+                    return self.authentication_failed("invalid public key");
+                };
+
+                challenge(
+                    connection_state,
+                    Credential::PublicKey(public_key),
+                    String::new(),
+                )
+            }
+            Cmd::Enroll { code, public_key } => {
+                let public_key = match public_key.as_deref().map(auth::normalize_public_key) {
+                    Some(None) => return Cmd::error("invalid public key"),
+                    public_key => public_key.flatten(),
+                };
+                let enrolled = database::enroll(
+                    &self.sqlite,
+                    &token::code_hash(&code),
+                    public_key.as_deref(),
+                )
+                .await;
+
+                match enrolled {
+                    Ok(Some((hostname, token))) => {
+                        Span::current().record("hostname", &hostname);
+                        info!(registered_key = public_key.is_some(), "enrolled");
+
+                        Cmd::Enrolled {
+                            hostname,
+                            token: token.map(Secret),
+                        }
+                    }
+                    Ok(None) => self.authentication_failed("invalid or expired enrollment code"),
+                    Err(_) => Cmd::error("cannot enroll"),
+                }
+            }
+            Cmd::Bearer { jwt } => {
+                let Some(verifier) = &self.jwt else {
+                    return self.authentication_failed("JWTs are not accepted");
+                };
+                let bearer = match verifier.verify(&jwt) {
+                    Ok(bearer) => bearer,
+                    Err(message) => return self.authentication_failed(message),
+                };
+
+                Span::current().record("hostname", &bearer.hostname);
+                info!(
+                    permission_level = ?bearer.permission_level,
+                    identity = "jwt",
+                    "authenticated"
+                );
+
+                self.authorize(
+                    connection_state,
+                    server_ptls,
+                    peer,
+                    ConnectionState::Authorized {
+                        hostname: bearer.hostname,
+                        permission_level: bearer.permission_level,
+                        token_id: None,
+                        expires_at: Some(bearer.expires_at),
+                        stepped_up: None,
+                    },
+                )
+                .await
+            }
+            Cmd::Authenticate { proof } => {
+                // any answer uses the challenge up, so it cannot be replayed
+                let ConnectionState::Challenged {
+                    credential,
+                    nonce,
+                    issued,
+                } = std::mem::replace(connection_state, ConnectionState::Socket)
+                else {
+                    return self.authentication_failed("no challenge to answer");
+                };
+                if issued.elapsed() > CHALLENGE_TIMEOUT {
+                    return self.authentication_failed("challenge expired");
+                }
+
+                let (client, identity) = match &credential {
+                    Credential::Token(token_id) => (
+                        self.token_client(token_id, &nonce, &proof).await,
+                        format!("token {token_id}"),
+                    ),
+                    Credential::PublicKey(public_key) => (
+                        self.key_client(public_key, &nonce, &proof)
+                            .await
+                            .ok_or("invalid key or signature"),
+                        format!("key {}", auth::fingerprint(public_key).unwrap_or_default()),
+                    ),
+                };
+                let (hostname, permission_level) = match client {
+                    Ok(client) => client,
+                    Err(message) => return self.authentication_failed(message),
+                };
+                let Ok(permission_level) = bincode::deserialize(&permission_level) else {
+                    return self.authentication_failed("invalid permission level");
+                };
+
+                Span::current().record("hostname", &hostname);
+                info!(?permission_level, identity, "authenticated");
+
+                self.authorize(
+                    connection_state,
+                    server_ptls,
+                    peer,
+                    ConnectionState::Authorized {
+                        hostname,
+                        permission_level,
+                        token_id: match credential {
+                            Credential::Token(token_id) => Some(token_id),
+                            Credential::PublicKey(_) => None,
+                        },
+                        expires_at: None,
+                        stepped_up: None,
+                    },
+                )
+                .await
+            }
+            _ => Cmd::error("not authenticated"),
+        }
+    }
+
+    /// Moves the connection to the `authorized` state and registers it as the
+    /// control connection of its client if the client can share ports, unless
+    /// the client has one. Refused if the client may not authenticate from
+    /// `peer`.
+    async fn authorize(
+        &self,
+        connection_state: &mut ConnectionState,
+        server_ptls: &Arc<Ptls<OwnedReadHalf, OwnedWriteHalf>>,
+        peer: SocketAddr,
+        authorized: ConnectionState,
+    ) -> Cmd {
+        if let ConnectionState::Authorized { hostname, .. } = &authorized {
+            if let Err(error) = self.check_source(hostname, peer).await {
+                return error;
+            }
+        }
+        let mut connections = self.connections.lock().await;
+
+        if let ConnectionState::Authorized {
+            hostname,
+            permission_level,
+            ..
+        } = &authorized
+        {
+            if permission_level.at_least(&PermissionLevel::Node)
+                && connections.get(hostname).is_none()
+            {
+                connections.insert(hostname.clone(), Arc::clone(server_ptls));
+            }
+        }
+        *connection_state = authorized;
+
+        Cmd::Ok
+    }
+
+    /// Checks that the client `hostname` may authenticate from `peer`,
+    /// auditing a denial. Clients outside the database are not restricted.
+    pub(crate) async fn check_source(&self, hostname: &str, peer: SocketAddr) -> Result<(), Cmd> {
+        let allowed = match database::allowed_sources(&self.sqlite, hostname).await {
+            Ok(Some(allowed)) => allowed,
+            Ok(None) => return Ok(()),
+            Err(_) => return Err(Cmd::error("cannot read allowed sources")),
+        };
+        if sources::allows(&allowed, peer.ip()) {
+            return Ok(());
+        }
+
+        self.audit(AuditEntry {
+            timestamp: database::now(),
+            actor: Some(hostname.to_string()),
+            peer: peer.to_string(),
+            action: String::from("deny_source"),
+            target: Some(hostname.to_string()),
+            outcome: String::from("address not allowed"),
+        })
+        .await;
+        Err(self.authentication_failed("address not allowed"))
+    }
+
+    /// Hostname and serialized permission level of the client whose token
+    /// `token_id` the HMAC `proof` proves.
+    async fn token_client(
+        &self,
+        token_id: &str,
+        nonce: &[u8],
+        proof: &[u8],
+    ) -> Result<(String, Vec<u8>), &'static str> {
+        let invalid = "invalid token or proof";
+        let client = database::token_owner(&self.sqlite, token_id)
+            .await
+            .ok()
+            .flatten()
+            .ok_or(invalid)?;
+        let stored_key = client.stored_key.as_deref().ok_or(invalid)?;
+
+        if !auth::verify(stored_key, nonce, token_id, proof) {
+            return Err(invalid);
+        }
+        // only told to those who prove the token
+        if client.expired() {
+            return Err("token expired");
+        }
+
+        Ok((client.hostname, client.permission_level))
+    }
+
+    /// Whether the token `id` still exists and has not expired.
+    pub(crate) async fn token_valid(&self, id: &str) -> bool {
+        database::token_owner(&self.sqlite, id)
+            .await
+            .is_ok_and(|client| client.is_some_and(|client| !client.expired()))
+    }
+
+    /// Resolves once the token in `token_id` is revoked, `token_id` being the
+    /// token the connection currently authenticates with.
+    pub(crate) async fn revoked(&self, token_id: &Mutex<Option<String>>) {
+        let mut revocations = self.revocations.subscribe();
+
+        loop {
+            let revoked = match revocations.recv().await {
+                Ok(revoked) => token_id.lock().unwrap().as_ref() == Some(&revoked),
+                // revocations may have been missed, the database knows
+                Err(RecvError::Lagged(_)) => {
+                    let current = token_id.lock().unwrap().clone();
+                    match current {
+                        Some(current) => !self.token_valid(&current).await,
+                        None => false,
+                    }
+                }
+                Err(RecvError::Closed) => return std::future::pending().await,
+            };
+
+            if revoked {
+                return;
+            }
+        }
+    }
+
+    /// Hostname and serialized permission level of the client that registered
+    /// `public_key`, if `signature` is made with its private key.
+    async fn key_client(
+        &self,
+        public_key: &str,
+        nonce: &[u8],
+        signature: &[u8],
+    ) -> Option<(String, Vec<u8>)> {
+        if !auth::verify_signature(public_key, nonce, signature) {
+            return None;
+        }
+        let client = database::key_owner(&self.sqlite, public_key).await.ok()??;
+
+        Some((client.hostname, client.permission_level))
+    }
+
+    /// Checks a TOTP code of the client `hostname`, which cannot be used again.
+    pub(crate) async fn check_totp(&self, hostname: &str, code: &str) -> Result<(), Cmd> {
+        let secret = match database::totp_secrets(&self.sqlite, hostname).await {
+            Ok(secrets) => secrets.and_then(|secrets| secrets.active),
+            Err(_) => return Err(Cmd::error("cannot read TOTP secret")),
+        };
+        let Some(secret) = secret else {
+            return Err(Cmd::error("TOTP is not enabled"));
+        };
+        let Some(step) = totp::verify(&secret, code, database::now()) else {
+            return Err(self.authentication_failed("invalid TOTP code"));
+        };
+
+        match database::use_totp_step(&self.sqlite, hostname, step).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(self.authentication_failed("TOTP code already used")),
+            Err(_) => Err(Cmd::error("cannot check TOTP code")),
+        }
+    }
+
+    /// Whether the client `hostname` may run admin commands, which requires
+    /// a step-up within the grace window if it enabled TOTP.
+    pub(crate) async fn stepped_up(&self, hostname: &str, stepped_up: Option<Instant>) -> bool {
+        if stepped_up.is_some_and(|stepped_up| stepped_up.elapsed() <= self.totp_grace) {
+            return true;
+        }
+
+        // clients outside the database, like JWT bearers, have no secret
+        database::totp_secrets(&self.sqlite, hostname)
+            .await
+            .is_ok_and(|secrets| secrets.is_none_or(|secrets| secrets.active.is_none()))
+    }
+
+    /// Checks that a client of `permission_level` may change the credentials
+    /// of the client `username`, as adding that client would require.
+    pub(crate) async fn may_manage(
+        &self,
+        permission_level: &PermissionLevel,
+        username: &str,
+    ) -> Result<(), Cmd> {
+        match database::permission_level(&self.sqlite, username).await {
+            Ok(Some(level)) if permission_level.at_least(&level.required_to_manage()) => Ok(()),
+            Ok(Some(_)) => Err(Cmd::error("permission denied")),
+            Ok(None) => Err(Cmd::error("no such client")),
+            Err(_) => Err(Cmd::error("cannot read client")),
+        }
+    }
+
+    pub(crate) fn authentication_failed(&self, message: &str) -> Cmd {
+        warn!(message, "authentication failed");
+        self.metrics.auth_failures.fetch_add(1, Ordering::Relaxed);
+
+        Cmd::AuthenticationFailed {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Whether `cmd` is an admin command, which clients with TOTP enabled have to
+/// step up for. Confirming an enrollment proves a code by itself.
+pub(crate) fn needs_step_up(cmd: &Cmd) -> bool {
+    matches!(cmd.minimum_permission_level(), PermissionLevel::Admin(_))
+        && !matches!(cmd, Cmd::ConfirmTotp { .. })
+}
+
+/// Resolves once the credential of the connection expires, `expiry` being
+/// its expiry in unix milliseconds if it has one.
+pub(crate) async fn expired(mut expiry: watch::Receiver<Option<u64>>) {
+    loop {
+        let expires_at = *expiry.borrow_and_update();
+        let deadline = async {
+            match expires_at {
+                Some(expires_at) => {
+                    let left = expires_at.saturating_sub(database::now());
+                    tokio::time::sleep(Duration::from_millis(left)).await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            () = deadline => return,
+            changed = expiry.changed() => {
+                if changed.is_err() {
+                    return std::future::pending().await;
+                }
+            }
+        }
+    }
+}
+
+/// Challenges a connection to prove `credential` with a fresh nonce.
+fn challenge(
+    connection_state: &mut ConnectionState,
+    credential: Credential,
+    setting: String,
+) -> Cmd {
+    let nonce = rand::thread_rng().gen::<[u8; 32]>().to_vec();
+    *connection_state = ConnectionState::Challenged {
+        credential,
+        nonce: nonce.clone(),
+        issued: Instant::now(),
+    };
+
+    Cmd::Challenge { nonce, setting }
+}
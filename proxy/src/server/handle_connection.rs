@@ -1,67 +1,317 @@
-use crate::connection::*;
+use super::{
+    audit, lockout, metrics::Counted, rate_limit::Limited, tls, traffic::SessionTraffic,
+    SHARE_ATTEMPTS, SHARE_RETRY,
+};
+use crate::{connection::*, database, token, totp};
 use ptls::Ptls;
-use rand::Rng;
-use std::{sync::Arc, time::Duration};
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{watch, OwnedSemaphorePermit},
+    time::timeout_at,
 };
+use tracing::{debug, info, info_span, warn, Instrument, Span};
 use util::*;
 
 impl super::Server {
-    pub(crate) async fn handle_connection(self: Arc<Self>, tcp: TcpStream) -> Option<()> {
+    pub(crate) async fn handle_connection(
+        self: Arc<Self>,
+        tcp: TcpStream,
+        peer: SocketAddr,
+        permit: OwnedSemaphorePermit,
+    ) -> Option<()> {
+        // held until the connection authenticates
+        let mut unauthenticated = Some(permit);
+        let deadline = tokio::time::Instant::now() + self.auth_timeout;
+
         let mut server_ptls = Ptls::new(tcp.into_split(), self.private_key.clone());
-        server_ptls.handshake().await.ok()?;
+        let started = Instant::now();
+        match timeout_at(deadline, server_ptls.handshake()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => {
+                debug!("handshake failed");
+                return None;
+            }
+            Err(_) => {
+                self.metrics.auth_timeouts.fetch_add(1, Ordering::Relaxed);
+                debug!("handshake timed out");
+                return None;
+            }
+        }
+        self.metrics.observe_handshake(started.elapsed());
         let server_ptls = Arc::new(server_ptls);
 
         let mut connection_state = ConnectionState::Socket;
 
-        while let Ok(cmd) = server_ptls.receive().await {
-            let cmd: Cmd = if let Ok(cmd) = bincode::deserialize(&cmd) {
-                cmd
-            } else {
-                continue;
-            };
+        // token of the connection, compared to the revoked ones
+        let token_id = std::sync::Mutex::new(None);
+        // expiry of the JWT of the connection
+        let (expiry, expiry_receiver) = watch::channel(None);
 
-            if let Some(result) = self
-                .handle_command(cmd, &mut connection_state, &server_ptls)
-                .await
-            {
+        let commands = async {
+            loop {
+                let received = if unauthenticated.is_some() {
+                    let Ok(received) = timeout_at(deadline, server_ptls.receive()).await else {
+                        self.metrics.auth_timeouts.fetch_add(1, Ordering::Relaxed);
+                        debug!("authentication timed out");
+                        break;
+                    };
+                    received
+                } else {
+                    server_ptls.receive().await
+                };
+                let Ok(cmd) = received else {
+                    break;
+                };
+                let cmd: Cmd = if let Ok(cmd) = bincode::deserialize(&cmd) {
+                    cmd
+                } else {
+                    continue;
+                };
+
+                let audited = audit::describe(&cmd);
+                let redeeming = matches!(cmd, Cmd::Redeem { .. });
+                let authenticating = matches!(
+                    cmd,
+                    Cmd::Authenticate { .. } | Cmd::StepUp { .. } | Cmd::ConfirmTotp { .. }
+                );
+                let subjects = lockout::subjects(peer.ip(), &cmd, &connection_state);
+
+                let (result, locked) = match self.lockout_refusal(&subjects) {
+                    Some(refusal) => {
+                        // a refused answer uses the challenge up all the same
+                        if let ConnectionState::Challenged { .. } = connection_state {
+                            connection_state = ConnectionState::Socket;
+                        }
+                        (refusal, true)
+                    }
+                    None => (
+                        self.handle_command(cmd, &mut connection_state, &server_ptls, peer)
+                            .await,
+                        false,
+                    ),
+                };
+
+                // a ticket admits its command only, even if the command failed
+                if redeeming && matches!(connection_state, ConnectionState::Authorized { .. }) {
+                    connection_state = ConnectionState::Socket;
+                }
+                if matches!(
+                    connection_state,
+                    ConnectionState::Authorized { .. } | ConnectionState::PortForward { .. }
+                ) {
+                    unauthenticated = None;
+                }
+
+                // follows the command without an await in between, so a rotation
+                // does not disconnect its own session
+                *token_id.lock().unwrap() = match &connection_state {
+                    ConnectionState::Authorized { token_id, .. }
+                    | ConnectionState::PortForward { token_id, .. } => token_id.clone(),
+                    _ => None,
+                };
+                expiry.send_replace(match &connection_state {
+                    ConnectionState::Authorized { expires_at, .. } => *expires_at,
+                    _ => None,
+                });
+
+                if let Some((action, target)) = audited {
+                    let actor = match &connection_state {
+                        ConnectionState::Socket | ConnectionState::Challenged { .. } => None,
+                        ConnectionState::Authorized { hostname, .. }
+                        | ConnectionState::PortForward { hostname, .. } => Some(hostname.clone()),
+                    };
+                    let outcome = match &result {
+                        Cmd::Error { message }
+                        | Cmd::AuthenticationFailed { message }
+                        | Cmd::QuotaExceeded { message } => message.clone(),
+                        _ => String::from("ok"),
+                    };
+
+                    self.audit(AuditEntry {
+                        timestamp: database::now(),
+                        actor,
+                        peer: peer.to_string(),
+                        action: action.to_string(),
+                        target,
+                        outcome,
+                    })
+                    .await;
+                }
+                match &result {
+                    Cmd::AuthenticationFailed { .. } if !locked => {
+                        self.count_failure(&subjects, peer).await;
+                    }
+                    Cmd::Ok if authenticating => self.forget_failures(&subjects),
+                    _ => {}
+                }
+                if let Cmd::Error { message }
+                | Cmd::AuthenticationFailed { message }
+                | Cmd::QuotaExceeded { message } = &result
+                {
+                    debug!(message, "command failed");
+                }
                 server_ptls
                     .send(&bincode::serialize(&result).ok()?)
                     .await
                     .ok()?;
+
+                if let ConnectionState::PortForward { .. } = connection_state {
+                    break;
+                }
             }
 
-            if let ConnectionState::PortForward { .. } = connection_state {
-                break;
+            Some(())
+        };
+        let disconnected = tokio::select! {
+            served = commands => served.map(|()| None),
+            () = self.revoked(&token_id) => Some(Some("token revoked")),
+            () = super::auth::expired(expiry_receiver) => Some(Some("JWT expired")),
+        };
+
+        // a control connection is unregistered however it ends, so its client
+        // can register again when it reconnects
+        if let ConnectionState::Authorized { hostname, .. }
+        | ConnectionState::PortForward { hostname, .. } = &connection_state
+        {
+            let mut connections = self.connections.lock().await;
+            if connections
+                .get(hostname)
+                .is_some_and(|connection| Arc::ptr_eq(connection, &server_ptls))
+            {
+                connections.remove(hostname);
             }
         }
 
-        if let ConnectionState::PortForward { kind, id, .. } = connection_state {
+        if let Some(reason) = disconnected? {
+            info!(reason, "disconnecting");
+            return None;
+        }
+
+        if let ConnectionState::PortForward {
+            kind,
+            id,
+            hostname,
+            port,
+            permit,
+            token_id: _,
+        } = connection_state
+        {
+            let span = info_span!("forward", session_id = id, %hostname, port, ?kind);
+
             match kind {
                 ForwardKind::Share => {
-                    let receiver;
-                    loop {
-                        if let Some(r) = self.forward_connections.lock().await.remove(&id) {
-                            receiver = r;
-                            break;
-                        }
+                    async {
+                        let Some(offer) = self.take_offer(id).await else {
+                            debug!("requester did not pair in time");
+                            return;
+                        };
+                        let requester_token_id = std::sync::Mutex::new(offer.token_id);
+                        let (target_r, target_w) = offer.receiver;
+                        let (r, w) = Arc::into_inner(server_ptls).unwrap().into_inner();
+                        let started_at = database::now();
+                        let started = Instant::now();
 
-                        tokio::time::sleep(Duration::from_millis(100)).await;
-                    }
+                        let _session = self.metrics.session(&hostname, port);
+                        let traffic = SessionTraffic::default();
+                        let r = Counted {
+                            inner: Counted {
+                                inner: Limited::new(
+                                    r,
+                                    self.buckets(&hostname, &offer.requester).await,
+                                ),
+                                counter: &traffic.down,
+                            },
+                            counter: &self.metrics.bytes_from_node,
+                        };
+                        let target_r = Counted {
+                            inner: Counted {
+                                inner: Limited::new(
+                                    target_r,
+                                    self.buckets(&offer.requester, &hostname).await,
+                                ),
+                                counter: &traffic.up,
+                            },
+                            counter: &self.metrics.bytes_to_node,
+                        };
 
-                    let (target_r, target_w) = receiver;
-                    let (r, w) = Arc::into_inner(server_ptls).unwrap().into_inner();
+                        info!(requester = offer.requester, "forward started");
+                        let parties = [offer.requester.as_str(), hostname.as_str()];
+                        let stopped = tokio::select! {
+                            transfer = copy_bidirectional((r, w), (target_r, target_w)) => {
+                                Ok(transfer)
+                            }
+                            () = self.meter_traffic(&traffic, parties) => {
+                                Err("transfer quota used up")
+                            }
+                            () = self.revoked(&token_id) => Err("token revoked"),
+                            () = self.revoked(&requester_token_id) => Err("token revoked"),
+                        };
+                        let (transfer, close_reason) = match stopped {
+                            Ok(transfer) => {
+                                let close_reason = match &transfer.error {
+                                    Some(error) => format!("error: {error}"),
+                                    None => String::from("closed"),
+                                };
+                                (transfer, close_reason)
+                            }
+                            Err(reason) => {
+                                let transfer = Transfer {
+                                    forward: traffic.down.load(Ordering::Relaxed),
+                                    backward: traffic.up.load(Ordering::Relaxed),
+                                    duration: started.elapsed(),
+                                    error: None,
+                                };
+                                (transfer, String::from(reason))
+                            }
+                        };
+                        self.charge_traffic(&traffic, parties).await;
+                        info!(
+                            bytes_up = transfer.backward,
+                            bytes_down = transfer.forward,
+                            duration = ?transfer.duration,
+                            close_reason,
+                            "forward closed"
+                        );
 
-                    copy_bidirectional((r, w), (target_r, target_w)).await;
+                        let session = SessionInfo {
+                            id,
+                            requester: offer.requester,
+                            node: hostname.clone(),
+                            port,
+                            started_at,
+                            ended_at: database::now(),
+                            bytes_up: transfer.backward,
+                            bytes_down: transfer.forward,
+                            close_reason,
+                        };
+                        if let Err(error) = database::record_session(&self.sqlite, &session).await {
+                            warn!(%error, "cannot record session");
+                        }
+                    }
+                    .instrument(span)
+                    .await
                 }
-                ForwardKind::Receive => {
-                    let mut forward_connections = self.forward_connections.lock().await;
-
+                ForwardKind::Receive { node } => {
                     // TODO: REMOVE UNWRAP
-                    forward_connections
-                        .insert(id, Arc::into_inner(server_ptls).unwrap().into_inner());
+                    let (r, w) = Arc::into_inner(server_ptls).unwrap().into_inner();
+                    let offer = Offer {
+                        requester: hostname,
+                        node,
+                        port,
+                        receiver: (Box::new(r), Box::new(w)),
+                        permit,
+                        token_id: token_id.into_inner().unwrap(),
+                    };
+                    self.offer(id, offer).instrument(span.clone()).await;
+                    span.in_scope(|| debug!("waiting for the node"));
                 }
             }
         }
@@ -74,105 +324,591 @@ impl super::Server {
         cmd: Cmd,
         connection_state: &mut ConnectionState,
         server_ptls: &Arc<Ptls<OwnedReadHalf, OwnedWriteHalf>>,
-    ) -> Option<Cmd> {
-        println!("handled: {cmd:?} {connection_state:?}");
-        match &connection_state {
-            ConnectionState::Socket => {
-                if let Cmd::Authenticate { token } = cmd {
-                    let token = String::from_utf8(token).ok()?;
-                    let client = sqlx::query!(
-                        "SELECT hostname, permission_level FROM clients WHERE key = ?",
-                        token
-                    )
-                    .fetch_optional(&self.sqlite)
-                    .await;
+        peer: SocketAddr,
+    ) -> Cmd {
+        debug!(?cmd, "command received");
+        let cmd = match (cmd, &connection_state) {
+            (Cmd::Redeem { ticket }, ConnectionState::Socket) => {
+                let claims = match self.tickets.redeem(&ticket) {
+                    Ok(claims) => claims,
+                    Err(message) => return self.authentication_failed(message),
+                };
+                if let Some(token_id) = &claims.token_id {
+                    if !self.token_valid(token_id).await {
+                        return self.authentication_failed("token revoked or expired");
+                    }
+                }
+                if let Err(error) = self.check_source(&claims.hostname, peer).await {
+                    return error;
+                }
 
-                    if let Ok(Some(client)) = client {
-                        let mut connections = self.connections.lock().await;
+                Span::current().record("hostname", &claims.hostname);
+                debug!(session_id = claims.session_id, "ticket redeemed");
 
-                        *connection_state = ConnectionState::Authorized {
-                            hostname: client.hostname.clone(),
-                            permission_level: bincode::deserialize(&client.permission_level)
-                                .ok()?,
-                        };
+                *connection_state = ConnectionState::Authorized {
+                    hostname: claims.hostname,
+                    permission_level: claims.permission_level,
+                    token_id: claims.token_id,
+                    expires_at: None,
+                    stepped_up: None,
+                };
+                claims.purpose.command()
+            }
+            (cmd, _) => cmd,
+        };
 
-                        if connections.get(&client.hostname).is_some() {
-                            return None;
-                        }
-                        connections.insert(client.hostname, Arc::clone(server_ptls));
-                    }
-                }
-                None
+        match &connection_state {
+            ConnectionState::Socket | ConnectionState::Challenged { .. } => {
+                self.authenticate(cmd, connection_state, server_ptls, peer)
+                    .await
             }
             ConnectionState::Authorized {
                 permission_level,
                 hostname,
-            } => match cmd {
-                Cmd::SharePort { port, id } => {
-                    *connection_state = ConnectionState::PortForward {
-                        hostname: hostname.clone(),
-                        kind: ForwardKind::Share,
-                        port,
-                        id,
-                    };
-                    None
+                token_id,
+                expires_at,
+                stepped_up,
+            } => {
+                if !permission_level.at_least(&cmd.minimum_permission_level()) {
+                    return Cmd::error("permission denied");
+                }
+                if super::auth::needs_step_up(&cmd) && !self.stepped_up(hostname, *stepped_up).await
+                {
+                    return Cmd::error("TOTP code required, step up first");
                 }
-                Cmd::GetPort {
-                    hostname: requested_hostname,
-                    port,
-                } => {
-                    if !permission_level.at_least(&PermissionLevel::Standart) {
-                        return None;
-                    }
-                    let id: u64 = rand::thread_rng().r#gen();
-                    loop {
-                        if let Some(connection) =
-                            self.connections.lock().await.get(&requested_hostname)
-                        {
-                            connection
-                                .send(&bincode::serialize(&Cmd::SharePort { port, id }).unwrap())
+
+                match cmd {
+                    Cmd::Noop => Cmd::Ok,
+                    Cmd::IssueTicket { purpose } => Cmd::Ticket {
+                        ticket: Secret(self.tickets.issue(
+                            hostname,
+                            permission_level.clone(),
+                            token_id.clone(),
+                            purpose,
+                        )),
+                    },
+                    Cmd::SharePort { port, id, .. } => {
+                        *connection_state = ConnectionState::PortForward {
+                            hostname: hostname.clone(),
+                            kind: ForwardKind::Share,
+                            port,
+                            id,
+                            permit: None,
+                            token_id: token_id.clone(),
+                        };
+                        Cmd::Ok
+                    }
+                    Cmd::GetPort {
+                        hostname: requested_hostname,
+                        port,
+                        protocol,
+                    } => {
+                        for (role, hostname) in
+                            [("client", hostname), ("node", &requested_hostname)]
+                        {
+                            if self.traffic_exhausted(hostname).await {
+                                let message =
+                                    format!("{role} {hostname} used up its transfer quota");
+                                info!(message, "quota exceeded");
+                                return Cmd::QuotaExceeded { message };
+                            }
+                        }
+
+                        let (Ok(requester_quotas), Ok(node_quotas)) = (
+                            database::quotas(&self.sqlite, hostname).await,
+                            database::quotas(&self.sqlite, &requested_hostname).await,
+                        ) else {
+                            return Cmd::error("cannot read quotas");
+                        };
+
+                        // quotas are admitted before the node is asked, so a
+                        // rejected request costs the node nothing
+                        let permit = match self.quotas.admit(
+                            (hostname, requester_quotas.unwrap_or_default()),
+                            (&requested_hostname, node_quotas.unwrap_or_default()),
+                        ) {
+                            Ok(permit) => permit,
+                            Err(message) => {
+                                info!(message, "quota exceeded");
+                                return Cmd::QuotaExceeded { message };
+                            }
+                        };
+
+                        let mut id = None;
+                        for attempt in 0..SHARE_ATTEMPTS {
+                            if attempt > 0 {
+                                tokio::time::sleep(SHARE_RETRY).await;
+                            }
+                            id = self
+                                .request_share(&requested_hostname, port, protocol)
+                                .await;
+                            if id.is_some() {
+                                break;
+                            }
+                        }
+                        let Some(id) = id else {
+                            drop(permit);
+                            return Cmd::error("node is not connected");
+                        };
+
+                        *connection_state = ConnectionState::PortForward {
+                            hostname: hostname.clone(),
+                            kind: ForwardKind::Receive {
+                                node: requested_hostname,
+                            },
+                            port,
+                            id,
+                            permit: Some(permit),
+                            token_id: token_id.clone(),
+                        };
+                        Cmd::Ok
+                    }
+                    Cmd::ListSessions { hostname, limit } => {
+                        match database::list_sessions(&self.sqlite, hostname.as_deref(), limit)
+                            .await
+                        {
+                            Ok(sessions) => Cmd::Sessions { sessions },
+                            Err(_) => Cmd::error("cannot list sessions"),
+                        }
+                    }
+                    Cmd::ListClients { after, limit } => {
+                        match database::list_clients(&self.sqlite, &after, limit).await {
+                            Ok(mut clients) => {
+                                for client in &mut clients {
+                                    client.usage = QuotaUsage {
+                                        transfer: client.usage.transfer,
+                                        ..self.quotas.usage(&client.hostname)
+                                    };
+                                }
+                                Cmd::Clients { clients }
+                            }
+                            Err(_) => Cmd::error("cannot list clients"),
+                        }
+                    }
+                    Cmd::SetRateLimits { username, limits } => {
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        match database::set_rate_limits(&self.sqlite, &username, &limits).await {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("no such client"),
+                            Err(_) => return Cmd::error("cannot set rate limits"),
+                        }
+
+                        self.rate_limits.update(&username, &limits);
+                        info!(username, ?limits, "rate limits set");
+                        Cmd::Ok
+                    }
+                    Cmd::SetQuotas { username, quotas } => {
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        match database::set_quotas(&self.sqlite, &username, &quotas).await {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("no such client"),
+                            Err(_) => return Cmd::error("cannot set quotas"),
+                        }
+
+                        info!(username, ?quotas, "quotas set");
+                        Cmd::Ok
+                    }
+                    Cmd::ListTraffic { hostname, limit } => {
+                        match database::list_traffic(&self.sqlite, hostname.as_deref(), limit).await
+                        {
+                            Ok(usage) => Cmd::Traffic { usage },
+                            Err(_) => Cmd::error("cannot list traffic"),
+                        }
+                    }
+                    Cmd::ListAudit { filter, limit } => {
+                        match database::list_audit(&self.sqlite, &filter, limit).await {
+                            Ok(entries) => Cmd::Audit { entries },
+                            Err(_) => Cmd::error("cannot list audit log"),
+                        }
+                    }
+                    Cmd::ResetTraffic { username } => {
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        if database::reset_traffic(&self.sqlite, &username)
+                            .await
+                            .is_err()
+                        {
+                            return Cmd::error("cannot reset traffic");
+                        }
+
+                        info!(username, "traffic reset");
+                        Cmd::Ok
+                    }
+                    Cmd::AddClient {
+                        username,
+                        permission_level,
+                        public_key,
+                    } => {
+                        let public_key = match public_key.as_deref().map(auth::normalize_public_key)
+                        {
+                            Some(None) => return Cmd::error("invalid public key"),
+                            public_key => public_key.flatten(),
+                        };
+                        let token = match database::add_client(
+                            &self.sqlite,
+                            &username,
+                            &permission_level,
+                            public_key.as_deref(),
+                        )
+                        .await
+                        {
+                            Ok(Some(token)) => token,
+                            Ok(None) => return Cmd::error("client exists"),
+                            Err(_) => return Cmd::error("cannot add client"),
+                        };
+
+                        info!(username, ?permission_level, "client added");
+                        match token {
+                            Some(token) => Cmd::Token {
+                                token: Secret(token),
+                            },
+                            None => Cmd::Ok,
+                        }
+                    }
+                    Cmd::SetPublicKey {
+                        username,
+                        public_key,
+                    } => {
+                        let public_key = match public_key.as_deref().map(auth::normalize_public_key)
+                        {
+                            Some(None) => return Cmd::error("invalid public key"),
+                            public_key => public_key.flatten(),
+                        };
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        if database::set_public_key(&self.sqlite, &username, public_key.as_deref())
+                            .await
+                            .is_err()
+                        {
+                            return Cmd::error("cannot set public key");
+                        }
+
+                        info!(
+                            username,
+                            registered = public_key.is_some(),
+                            "public key set"
+                        );
+                        Cmd::Ok
+                    }
+                    Cmd::SetAllowedSources { username, sources } => {
+                        let sources = match sources
+                            .as_deref()
+                            .map(crate::sources::normalize)
+                            .transpose()
+                        {
+                            Ok(sources) => sources,
+                            Err(message) => return Cmd::error(message),
+                        };
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        match database::set_allowed_sources(
+                            &self.sqlite,
+                            &username,
+                            sources.as_deref(),
+                        )
+                        .await
+                        {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("no such client"),
+                            Err(_) => return Cmd::error("cannot set allowed sources"),
+                        }
+
+                        info!(username, sources, "allowed sources set");
+                        Cmd::Ok
+                    }
+                    Cmd::CreateEnrollment {
+                        hostname: enrolled,
+                        permission_level,
+                        valid_for,
+                    } => {
+                        let Some(expires_at) = token::enrollment_expiry(valid_for) else {
+                            return Cmd::error("invalid validity");
+                        };
+                        let (code, code_hash) = token::enrollment_code();
+                        match database::add_enrollment(
+                            &self.sqlite,
+                            &code_hash,
+                            &enrolled,
+                            &permission_level,
+                            expires_at,
+                        )
+                        .await
+                        {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("client exists"),
+                            Err(_) => return Cmd::error("cannot create enrollment"),
+                        }
+
+                        info!(hostname = enrolled, ?permission_level, "enrollment created");
+                        Cmd::EnrollmentCode { code: Secret(code) }
+                    }
+                    Cmd::AddToken {
+                        username,
+                        label,
+                        expires_at,
+                    } => {
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        let token =
+                            match database::add_token(&self.sqlite, &username, &label, expires_at)
                                 .await
-                                .ok();
+                            {
+                                Ok(Some(token)) => token,
+                                Ok(None) => return Cmd::error("no such client"),
+                                Err(_) => return Cmd::error("cannot add token"),
+                            };
+
+                        info!(username, label, ?expires_at, "token added");
+                        Cmd::Token {
+                            token: Secret(token),
+                        }
+                    }
+                    Cmd::ListTokens { username } => {
+                        // others' tokens are managed like their credentials
+                        if username != *hostname {
+                            if let Err(error) = self.may_manage(permission_level, &username).await {
+                                return error;
+                            }
+                        }
+                        match database::list_tokens(&self.sqlite, &username).await {
+                            Ok(tokens) => Cmd::Tokens { tokens },
+                            Err(_) => Cmd::error("cannot list tokens"),
+                        }
+                    }
+                    Cmd::RevokeToken { id } => {
+                        let owner = match database::token_owner(&self.sqlite, &id).await {
+                            Ok(Some(owner)) => owner.hostname,
+                            Ok(None) => return Cmd::error("no such token"),
+                            Err(_) => return Cmd::error("cannot read token"),
+                        };
+                        if owner != *hostname {
+                            if let Err(error) = self.may_manage(permission_level, &owner).await {
+                                return error;
+                            }
+                        }
+                        match database::revoke_token(&self.sqlite, &id).await {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("no such token"),
+                            Err(_) => return Cmd::error("cannot revoke token"),
+                        }
+
+                        info!(token_id = id, "token revoked");
+                        self.revocations.send(id).ok();
+                        Cmd::Ok
+                    }
+                    Cmd::StepUp { code } => {
+                        if let Err(error) = self.check_totp(hostname, &code).await {
+                            return error;
+                        }
 
-                            *connection_state = ConnectionState::PortForward {
-                                hostname: hostname.clone(),
-                                kind: ForwardKind::Receive,
-                                port,
-                                id,
+                        debug!("stepped up");
+                        *connection_state = ConnectionState::Authorized {
+                            hostname: hostname.clone(),
+                            permission_level: permission_level.clone(),
+                            token_id: token_id.clone(),
+                            expires_at: *expires_at,
+                            stepped_up: Some(Instant::now()),
+                        };
+                        Cmd::Ok
+                    }
+                    Cmd::EnrollTotp => {
+                        let secret = totp::generate_secret();
+                        match database::set_pending_totp(&self.sqlite, hostname, &secret).await {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("no such client"),
+                            Err(_) => return Cmd::error("cannot enroll TOTP"),
+                        }
+
+                        Cmd::TotpSecret {
+                            secret: Secret(totp::base32(&secret)),
+                            uri: Secret(totp::uri(&secret, hostname)),
+                        }
+                    }
+                    Cmd::ConfirmTotp { code } => {
+                        let pending = match database::totp_secrets(&self.sqlite, hostname).await {
+                            Ok(secrets) => secrets.and_then(|secrets| secrets.pending),
+                            Err(_) => return Cmd::error("cannot read TOTP secret"),
+                        };
+                        let Some(secret) = pending else {
+                            return Cmd::error("no TOTP enrollment to confirm");
+                        };
+                        let Some(step) = totp::verify(&secret, &code, database::now()) else {
+                            return self.authentication_failed("invalid TOTP code");
+                        };
+                        match database::activate_totp(&self.sqlite, hostname, &secret, step).await {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("TOTP enrollment was replaced"),
+                            Err(_) => return Cmd::error("cannot confirm TOTP"),
+                        }
+
+                        info!("TOTP enabled");
+                        Cmd::Ok
+                    }
+                    Cmd::DisableTotp { username } => {
+                        // others' second factor is managed like their credentials
+                        if username != *hostname {
+                            if let Err(error) = self.may_manage(permission_level, &username).await {
+                                return error;
+                            }
+                        }
+                        match database::disable_totp(&self.sqlite, &username).await {
+                            Ok(true) => {}
+                            Ok(false) => return Cmd::error("no such client"),
+                            Err(_) => return Cmd::error("cannot disable TOTP"),
+                        }
+
+                        info!(username, "TOTP disabled");
+                        Cmd::Ok
+                    }
+                    Cmd::RotateToken => {
+                        let Some(old_id) = token_id.clone() else {
+                            return Cmd::error("not authenticated with a token");
+                        };
+                        let (new_id, token) =
+                            match database::rotate_token(&self.sqlite, &old_id).await {
+                                Ok(Some(rotated)) => rotated,
+                                Ok(None) => return Cmd::error("no such token"),
+                                Err(_) => return Cmd::error("cannot rotate token"),
                             };
 
-                            break;
+                        info!(old_id, new_id, "token rotated");
+                        *connection_state = ConnectionState::Authorized {
+                            hostname: hostname.clone(),
+                            permission_level: permission_level.clone(),
+                            token_id: Some(new_id),
+                            expires_at: None,
+                            stepped_up: *stepped_up,
+                        };
+                        // the session itself is moved to the new token before
+                        // it could see the revocation
+                        self.revocations.send(old_id).ok();
+                        Cmd::Token {
+                            token: Secret(token),
+                        }
+                    }
+                    Cmd::RemoveClient { username } => {
+                        if let Err(error) = self.may_manage(permission_level, &username).await {
+                            return error;
+                        }
+                        let token_ids = match database::remove_client(&self.sqlite, &username).await
+                        {
+                            Ok(Some(token_ids)) => token_ids,
+                            Ok(None) => return Cmd::error("no such client"),
+                            Err(_) => return Cmd::error("cannot remove client"),
                         };
+                        self.rate_limits.remove(&username);
+                        for id in token_ids {
+                            self.revocations.send(id).ok();
+                        }
 
-                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        info!(username, "client removed");
+                        Cmd::Ok
                     }
-                    None
-                }
-                Cmd::AddClient {
-                    username, token, ..
-                } => {
-                    if !permission_level.at_least(&PermissionLevel::Admin(0)) {
-                        return None;
+                    Cmd::AddHttpRoute {
+                        domain,
+                        hostname,
+                        port,
+                    } => {
+                        let domain = domain.to_lowercase();
+                        if sqlx::query!(
+                            "INSERT OR REPLACE INTO http_routes VALUES (?, ?, ?);",
+                            domain,
+                            hostname,
+                            port
+                        )
+                        .execute(&self.sqlite)
+                        .await
+                        .is_err()
+                        {
+                            return Cmd::error("cannot add http route");
+                        }
+
+                        info!(domain, hostname, port, "http route added");
+                        Cmd::Ok
                     }
+                    Cmd::RemoveHttpRoute { domain } => {
+                        let domain = domain.to_lowercase();
+                        if sqlx::query!("DELETE FROM http_routes WHERE domain = ?;", domain)
+                            .execute(&self.sqlite)
+                            .await
+                            .is_err()
+                        {
+                            return Cmd::error("cannot remove http route");
+                        }
 
-                    let blob = bincode::serialize(&util::PermissionLevel::Standart).unwrap();
-                    if sqlx::query_scalar!(
-                        "INSERT INTO clients SELECT ?, ?, ?;",
-                        username,
-                        blob,
-                        token
-                    )
-                    .fetch_optional(&self.sqlite)
-                    .await
-                    .is_ok()
-                    {
-                        println!("user added: {}", username)
-                    };
-                    None
+                        info!(domain, "http route removed");
+                        Cmd::Ok
+                    }
+                    Cmd::AddTlsRoute {
+                        server_name,
+                        hostname,
+                        port,
+                        termination,
+                    } => {
+                        let (certificate, private_key) = match termination {
+                            Some(TlsTermination {
+                                certificate,
+                                private_key,
+                            }) => {
+                                if tls::server_config(&certificate, &private_key).is_none() {
+                                    return Cmd::error(format!(
+                                        "cannot load certificate: {certificate}"
+                                    ));
+                                }
+                                (Some(certificate), Some(private_key))
+                            }
+                            None => (None, None),
+                        };
+
+                        let server_name = server_name.to_lowercase();
+                        if sqlx::query!(
+                            "INSERT OR REPLACE INTO tls_routes VALUES (?, ?, ?, ?, ?);",
+                            server_name,
+                            hostname,
+                            port,
+                            certificate,
+                            private_key
+                        )
+                        .execute(&self.sqlite)
+                        .await
+                        .is_err()
+                        {
+                            return Cmd::error("cannot add tls route");
+                        }
+
+                        self.tls_configs.forget(&server_name);
+                        info!(server_name, hostname, port, "tls route added");
+                        Cmd::Ok
+                    }
+                    Cmd::RemoveTlsRoute { server_name } => {
+                        let server_name = server_name.to_lowercase();
+                        if sqlx::query!(
+                            "DELETE FROM tls_routes WHERE server_name = ?;",
+                            server_name
+                        )
+                        .execute(&self.sqlite)
+                        .await
+                        .is_err()
+                        {
+                            return Cmd::error("cannot remove tls route");
+                        }
+
+                        self.tls_configs.forget(&server_name);
+                        info!(server_name, "tls route removed");
+                        Cmd::Ok
+                    }
+                    _ => Cmd::error("unsupported command"),
                 }
-                _ => None,
-            },
-            ConnectionState::PortForward { .. } => None,
+            }
+            ConnectionState::PortForward { .. } => Cmd::error("unsupported command"),
         }
     }
 }
@@ -0,0 +1,189 @@
+use crate::connection::Offer;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+use tracing::{debug, info, info_span, Instrument};
+use util::Protocol;
+
+/// Maximum size of the HTTP request head that is inspected for the `Host`
+/// header.
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// Connections whose request head is not complete in time are dropped.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl super::Server {
+    /// Serves plain HTTP, routing each connection to a node by its `Host`
+    /// header.
+    pub async fn serve_http<T: ToSocketAddrs>(self: Arc<Self>, addr: T) -> ! {
+        let listener = TcpListener::bind(addr).await.unwrap();
+
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => continue,
+            };
+
+            let this = Arc::clone(&self);
+            tokio::spawn(
+                this.handle_http(socket, peer)
+                    .instrument(info_span!("http", %peer)),
+            );
+        }
+    }
+
+    async fn handle_http(self: Arc<Self>, mut tcp: TcpStream, peer: SocketAddr) -> Option<()> {
+        let Ok(host) = tokio::time::timeout(HEAD_TIMEOUT, peek_host(&tcp)).await else {
+            debug!("request head timed out");
+            return respond(&mut tcp, "408 Request Timeout").await;
+        };
+        let host = match host? {
+            Some(host) => host,
+            None => return respond(&mut tcp, "400 Bad Request").await,
+        };
+
+        let route = sqlx::query!(
+            "SELECT hostname, port FROM http_routes WHERE domain = ?",
+            host
+        )
+        .fetch_optional(&self.sqlite)
+        .await
+        .ok()?;
+
+        let (hostname, port) = match route {
+            Some(route) => (route.hostname, u32::try_from(route.port).ok()?),
+            None => {
+                debug!(host, "no http route");
+                return respond(&mut tcp, "404 Not Found").await;
+            }
+        };
+
+        if self.traffic_exhausted(&hostname).await {
+            debug!(host, hostname, "node used up its transfer quota");
+            return respond(&mut tcp, "503 Service Unavailable").await;
+        }
+        let permit = match self.admit_routed(&hostname).await {
+            Ok(permit) => permit,
+            Err(message) => {
+                debug!(host, hostname, message, "quota exceeded");
+                return respond(&mut tcp, "503 Service Unavailable").await;
+            }
+        };
+
+        let id = match self.request_share(&hostname, port, Protocol::Tcp).await {
+            Some(id) => id,
+            None => {
+                debug!(host, hostname, "node is not connected");
+                return respond(&mut tcp, "502 Bad Gateway").await;
+            }
+        };
+        info!(host, hostname, port, session_id = id, "http request routed");
+
+        // The request head is only peeked, so the node receives it unchanged.
+        let (r, w) = tcp.into_split();
+        let offer = Offer {
+            requester: peer.to_string(),
+            node: hostname,
+            port,
+            receiver: (Box::new(r), Box::new(w)),
+            permit: Some(permit),
+            token_id: None,
+        };
+        self.offer(id, offer).await;
+
+        Some(())
+    }
+}
+
+/// Peeks the request head without consuming it and returns value of the
+/// `Host` header without the port. Returns `Some(None)` for malformed
+/// requests.
+async fn peek_host(tcp: &TcpStream) -> Option<Option<String>> {
+    let mut buf = vec![0; MAX_HEAD_SIZE];
+    let mut peeked = 0;
+
+    loop {
+        let n = tcp.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+
+        if let Some(end) = buf[..n].windows(4).position(|w| w == b"\r\n\r\n") {
+            return Some(parse_host(&buf[..end]));
+        }
+
+        if n == buf.len() {
+            return Some(None);
+        }
+
+        // peek returns immediately while the buffered head is incomplete.
+        if n == peeked {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        peeked = n;
+    }
+}
+
+fn parse_host(head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(head).ok()?;
+
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("host") {
+            return None;
+        }
+
+        let value = value.trim();
+        let host = match value.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => value,
+        };
+
+        Some(host.to_lowercase())
+    })
+}
+
+async fn respond(tcp: &mut TcpStream, status: &str) -> Option<()> {
+    tcp.write_all(
+        format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").as_bytes(),
+    )
+    .await
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(head: &str) -> Option<String> {
+        parse_host(head.as_bytes())
+    }
+
+    #[test]
+    fn host_is_lowercased_without_port() {
+        assert_eq!(
+            host("GET / HTTP/1.1\r\nHost: Example.COM:8080\r\nAccept: */*"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            host("GET / HTTP/1.1\r\nAccept: */*\r\nhOsT:example.com"),
+            Some("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn request_line_is_not_a_header() {
+        assert_eq!(host("Host: example.com / HTTP/1.1\r\nAccept: */*"), None);
+    }
+
+    #[test]
+    fn missing_or_truncated_host_is_none() {
+        assert_eq!(host("GET / HTTP/1.1\r\nAccept: */*"), None);
+        assert_eq!(host("GET / HTTP/1.1\r\nHost"), None);
+        assert_eq!(host("GET / HTTP/1.1"), None);
+        assert_eq!(host(""), None);
+        assert_eq!(parse_host(b"GET / HTTP/1.1\r\nHost: \xff"), None);
+    }
+}
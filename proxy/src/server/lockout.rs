@@ -0,0 +1,303 @@
+use crate::{
+    connection::{ConnectionState, Credential},
+    database,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+use util::{auth, AuditEntry, Cmd};
+
+/// Failed attempts tolerated before a lockout.
+const FREE_FAILURES: u32 = 5;
+
+/// First lockout, doubled with every further failure up to [`MAX_LOCKOUT`].
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+
+/// Failures are forgotten after this long without another one.
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+/// Forgotten failures are pruned once this many subjects are tracked.
+const PRUNE_AT: usize = 4096;
+
+/// What failed authentication attempts are counted against.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Subject {
+    Peer(IpAddr),
+    /// A token id, public key or client, as described in the audit log.
+    Identity(String),
+}
+
+/// Failed attempts of the subjects, locking them out with exponential
+/// backoff.
+#[derive(Default)]
+pub(crate) struct Lockouts {
+    failures: Mutex<HashMap<Subject, Failures>>,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Lockouts {
+    /// Time left of the lockout of `subject`, if it is locked out.
+    pub(crate) fn locked(&self, subject: &Subject) -> Option<Duration> {
+        self.locked_at(subject, Instant::now())
+    }
+
+    /// Counts a failed attempt of `subject`. Returns the lockout it caused.
+    pub(crate) fn fail(&self, subject: &Subject) -> Option<Duration> {
+        self.fail_at(subject, Instant::now())
+    }
+
+    fn locked_at(&self, subject: &Subject, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures.get(subject)?.locked_until?;
+
+        locked_until
+            .checked_duration_since(now)
+            .filter(|left| !left.is_zero())
+    }
+
+    fn fail_at(&self, subject: &Subject, now: Instant) -> Option<Duration> {
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() >= PRUNE_AT {
+            failures.retain(|_, failures| !failures.forgotten(now));
+        }
+
+        let failures = failures.entry(subject.clone()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if failures.forgotten(now) {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+
+        let excess = failures.count.checked_sub(FREE_FAILURES + 1)?;
+        let lockout = BASE_LOCKOUT
+            .saturating_mul(2u32.saturating_pow(excess))
+            .min(MAX_LOCKOUT);
+        failures.locked_until = Some(now + lockout);
+
+        Some(lockout)
+    }
+
+    /// Forgets the failures of `subject` after it authenticated.
+    pub(crate) fn succeed(&self, subject: &Subject) {
+        self.failures.lock().unwrap().remove(subject);
+    }
+}
+
+impl Failures {
+    fn forgotten(&self, now: Instant) -> bool {
+        now.duration_since(self.last) >= FORGET_AFTER
+            && self
+                .locked_until
+                .is_none_or(|locked_until| locked_until <= now)
+    }
+}
+
+impl super::Server {
+    /// Refusal of a command whose subjects include a locked out one.
+    pub(crate) fn lockout_refusal(&self, subjects: &[Subject]) -> Option<Cmd> {
+        let left = subjects
+            .iter()
+            .filter_map(|subject| self.lockouts.locked(subject))
+            .max()?;
+
+        Some(self.authentication_failed(&format!(
+            "too many failed attempts, retry in {}s",
+            left.as_secs() + 1
+        )))
+    }
+
+    /// Counts a failed authentication against `subjects`, auditing the
+    /// lockouts it causes.
+    pub(crate) async fn count_failure(&self, subjects: &[Subject], peer: SocketAddr) {
+        for subject in subjects {
+            let Some(lockout) = self.lockouts.fail(subject) else {
+                continue;
+            };
+
+            self.metrics.lockouts.fetch_add(1, Ordering::Relaxed);
+            warn!(%subject, ?lockout, "locked out");
+            self.audit(AuditEntry {
+                timestamp: database::now(),
+                actor: None,
+                peer: peer.to_string(),
+                action: String::from("lockout"),
+                target: Some(subject.to_string()),
+                outcome: format!("locked out for {}s", lockout.as_secs()),
+            })
+            .await;
+        }
+    }
+
+    /// Forgets the failures of the identity in `subjects` once it proved
+    /// itself. Those of the peer are kept, a peer may try many identities.
+    pub(crate) fn forget_failures(&self, subjects: &[Subject]) {
+        for subject in subjects {
+            if let Subject::Identity(_) = subject {
+                self.lockouts.succeed(subject);
+            }
+        }
+    }
+}
+
+/// Subjects a failure of `cmd` from `peer` is counted against: the peer
+/// address and the identity the command claims, if any. Only authentication
+/// commands have subjects, so a locked out address does not cut off the
+/// sessions that authenticated from it before.
+pub(crate) fn subjects(
+    peer: IpAddr,
+    cmd: &Cmd,
+    connection_state: &ConnectionState,
+) -> Vec<Subject> {
+    if !matches!(
+        cmd,
+        Cmd::Hello { .. }
+            | Cmd::KeyHello { .. }
+            | Cmd::Authenticate { .. }
+            | Cmd::Bearer { .. }
+            | Cmd::Redeem { .. }
+            | Cmd::Enroll { .. }
+            | Cmd::StepUp { .. }
+            | Cmd::ConfirmTotp { .. }
+    ) {
+        return Vec::new();
+    }
+
+    let identity = match (cmd, connection_state) {
+        (Cmd::Hello { token_id }, _) => Some(format!("token {token_id}")),
+        (Cmd::KeyHello { public_key }, _) => key_identity(public_key),
+        (Cmd::Authenticate { .. }, ConnectionState::Challenged { credential, .. }) => {
+            match credential {
+                Credential::Token(token_id) => Some(format!("token {token_id}")),
+                Credential::PublicKey(public_key) => key_identity(public_key),
+            }
+        }
+        (
+            Cmd::StepUp { .. } | Cmd::ConfirmTotp { .. },
+            ConnectionState::Authorized { hostname, .. },
+        ) => Some(format!("client {hostname}")),
+        _ => None,
+    };
+
+    let mut subjects = vec![Subject::Peer(peer)];
+    subjects.extend(identity.map(Subject::Identity));
+    subjects
+}
+
+fn key_identity(public_key: &str) -> Option<String> {
+    auth::fingerprint(public_key).map(|fingerprint| format!("key {fingerprint}"))
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Peer(ip) => write!(f, "peer {ip}"),
+            Self::Identity(identity) => write!(f, "{identity}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject() -> Subject {
+        Subject::Identity(String::from("token 0123456789abcdef"))
+    }
+
+    #[test]
+    fn tolerates_free_failures() {
+        let (lockouts, now) = (Lockouts::default(), Instant::now());
+
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(lockouts.fail_at(&subject(), now), None);
+        }
+        assert_eq!(lockouts.locked_at(&subject(), now), None);
+        assert_eq!(lockouts.fail_at(&subject(), now), Some(BASE_LOCKOUT));
+        assert_eq!(lockouts.locked_at(&subject(), now), Some(BASE_LOCKOUT));
+    }
+
+    #[test]
+    fn doubles_lockouts_up_to_the_cap() {
+        let (lockouts, now) = (Lockouts::default(), Instant::now());
+        for _ in 0..FREE_FAILURES {
+            lockouts.fail_at(&subject(), now);
+        }
+
+        let lockouts: Vec<_> = (0..10)
+            .map(|_| lockouts.fail_at(&subject(), now).unwrap().as_secs())
+            .collect();
+        assert_eq!(
+            lockouts,
+            [30, 60, 120, 240, 480, 960, 1920, 3600, 3600, 3600]
+        );
+    }
+
+    #[test]
+    fn lockouts_expire() {
+        let (lockouts, now) = (Lockouts::default(), Instant::now());
+        for _ in 0..=FREE_FAILURES {
+            lockouts.fail_at(&subject(), now);
+        }
+
+        let later = now + BASE_LOCKOUT / 2;
+        assert_eq!(
+            lockouts.locked_at(&subject(), later),
+            Some(BASE_LOCKOUT / 2)
+        );
+        assert_eq!(lockouts.locked_at(&subject(), now + BASE_LOCKOUT), None);
+    }
+
+    #[test]
+    fn forgets_failures_after_a_quiet_hour() {
+        let (lockouts, now) = (Lockouts::default(), Instant::now());
+        for _ in 0..=FREE_FAILURES {
+            lockouts.fail_at(&subject(), now);
+        }
+
+        let later = now + FORGET_AFTER;
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(lockouts.fail_at(&subject(), later), None);
+        }
+        assert_eq!(lockouts.fail_at(&subject(), later), Some(BASE_LOCKOUT));
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let (lockouts, now) = (Lockouts::default(), Instant::now());
+        for _ in 0..=FREE_FAILURES {
+            lockouts.fail_at(&subject(), now);
+        }
+
+        lockouts.succeed(&subject());
+        assert_eq!(lockouts.locked_at(&subject(), now), None);
+        assert_eq!(lockouts.fail_at(&subject(), now), None);
+    }
+
+    #[test]
+    fn counts_subjects_apart() {
+        let (lockouts, now) = (Lockouts::default(), Instant::now());
+        for _ in 0..=FREE_FAILURES {
+            lockouts.fail_at(&subject(), now);
+        }
+
+        let peer = Subject::Peer("192.0.2.1".parse().unwrap());
+        assert_eq!(lockouts.locked_at(&peer, now), None);
+        assert_eq!(lockouts.fail_at(&peer, now), None);
+    }
+}
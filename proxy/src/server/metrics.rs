@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+use tracing::{debug, info_span, Instrument};
+
+/// Upper bounds of the handshake latency buckets in seconds.
+const HANDSHAKE_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counters and gauges of the server, exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub(crate) auth_failures: AtomicU64,
+    pub(crate) pairing_timeouts: AtomicU64,
+    /// Subjects locked out after failed authentications.
+    pub(crate) lockouts: AtomicU64,
+    /// Connections refused since too many were not authenticated.
+    pub(crate) rejected_connections: AtomicU64,
+    /// Connections dropped since they did not authenticate in time.
+    pub(crate) auth_timeouts: AtomicU64,
+    /// Bytes from the requesters to the nodes.
+    pub(crate) bytes_to_node: AtomicU64,
+    /// Bytes from the nodes to the requesters.
+    pub(crate) bytes_from_node: AtomicU64,
+    /// Active sessions by node hostname and port.
+    sessions: Mutex<HashMap<(String, u32), u64>>,
+    handshake: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; HANDSHAKE_BUCKETS.len()],
+    count: AtomicU64,
+    /// Sum of the observations in microseconds.
+    sum: AtomicU64,
+}
+
+/// Active session gauge, decremented when dropped.
+pub(crate) struct SessionGuard<'a> {
+    metrics: &'a Metrics,
+    key: (String, u32),
+}
+
+/// Reader that adds the read bytes to a counter.
+pub(crate) struct Counted<'a, R> {
+    pub(crate) inner: R,
+    pub(crate) counter: &'a AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn observe_handshake(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bucket, bound) in self.handshake.buckets.iter().zip(HANDSHAKE_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.handshake.count.fetch_add(1, Ordering::Relaxed);
+        self.handshake
+            .sum
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts an active session to `port` of the node `hostname` until the
+    /// guard is dropped.
+    pub(crate) fn session(&self, hostname: &str, port: u32) -> SessionGuard<'_> {
+        let key = (hostname.to_string(), port);
+        *self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default() += 1;
+
+        SessionGuard { metrics: self, key }
+    }
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        let mut sessions = self.metrics.sessions.lock().unwrap();
+        if let Some(count) = sessions.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        self.counter
+            .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl super::Server {
+    /// Serves the metrics on `GET /metrics` over plain HTTP.
+    pub async fn serve_metrics<T: ToSocketAddrs>(self: Arc<Self>, addr: T) -> ! {
+        let listener = TcpListener::bind(addr).await.unwrap();
+
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => continue,
+            };
+
+            let this = Arc::clone(&self);
+            tokio::spawn(
+                this.handle_metrics(socket)
+                    .instrument(info_span!("metrics", %peer)),
+            );
+        }
+    }
+
+    async fn handle_metrics(self: Arc<Self>, mut tcp: TcpStream) -> Option<()> {
+        let mut buf = vec![0; 1024];
+        let n = tcp.read(&mut buf).await.ok()?;
+
+        let response = if buf[..n].starts_with(b"GET /metrics ") {
+            let body = self.render_metrics().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        } else {
+            debug!("unknown metrics request");
+            String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        };
+
+        tcp.write_all(response.as_bytes()).await.ok()
+    }
+
+    async fn render_metrics(&self) -> String {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+
+        let connected_nodes = self.connections.lock().await.len();
+        let pending = self.forward_connections.lock().await.len();
+
+        gauge(
+            &mut out,
+            "proxy_connected_nodes",
+            "Control connections of clients that can share ports.",
+            connected_nodes as u64,
+        );
+        gauge(
+            &mut out,
+            "proxy_pending_forward_connections",
+            "Requester streams waiting for their node.",
+            pending as u64,
+        );
+        gauge(
+            &mut out,
+            "proxy_unauthenticated_connections",
+            "Connections that have not authenticated yet.",
+            (self.max_unauthenticated - self.unauthenticated.available_permits()) as u64,
+        );
+
+        writeln!(
+            out,
+            "# HELP proxy_active_sessions Forwarding sessions by node and port.\n# TYPE proxy_active_sessions gauge"
+        )
+        .ok();
+        for ((hostname, port), count) in metrics.sessions.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "proxy_active_sessions{{hostname=\"{}\",port=\"{port}\"}} {count}",
+                escape(hostname)
+            )
+            .ok();
+        }
+
+        writeln!(
+            out,
+            "# HELP proxy_forwarded_bytes_total Bytes forwarded by direction.\n# TYPE proxy_forwarded_bytes_total counter"
+        )
+        .ok();
+        for (direction, counter) in [
+            ("to_node", &metrics.bytes_to_node),
+            ("from_node", &metrics.bytes_from_node),
+        ] {
+            writeln!(
+                out,
+                "proxy_forwarded_bytes_total{{direction=\"{direction}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            )
+            .ok();
+        }
+
+        counter(
+            &mut out,
+            "proxy_auth_failures_total",
+            "Failed authentications.",
+            metrics.auth_failures.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "proxy_lockouts_total",
+            "Peers and identities locked out after failed authentications.",
+            metrics.lockouts.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "proxy_rejected_connections_total",
+            "Connections refused since too many were not authenticated.",
+            metrics.rejected_connections.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "proxy_auth_timeouts_total",
+            "Connections dropped since they did not authenticate in time.",
+            metrics.auth_timeouts.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "proxy_pairing_timeouts_total",
+            "Forwarding streams that were not paired in time.",
+            metrics.pairing_timeouts.load(Ordering::Relaxed),
+        );
+
+        let histogram = &metrics.handshake;
+        writeln!(
+            out,
+            "# HELP proxy_handshake_seconds Latency of the ptls handshakes.\n# TYPE proxy_handshake_seconds histogram"
+        )
+        .ok();
+        for (bucket, bound) in histogram.buckets.iter().zip(HANDSHAKE_BUCKETS) {
+            writeln!(
+                out,
+                "proxy_handshake_seconds_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            )
+            .ok();
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        writeln!(out, "proxy_handshake_seconds_bucket{{le=\"+Inf\"}} {count}").ok();
+        writeln!(
+            out,
+            "proxy_handshake_seconds_sum {}",
+            histogram.sum.load(Ordering::Relaxed) as f64 / 1e6
+        )
+        .ok();
+        writeln!(out, "proxy_handshake_seconds_count {count}").ok();
+
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    )
+    .ok();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+    )
+    .ok();
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
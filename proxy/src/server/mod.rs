@@ -1,22 +1,71 @@
+pub mod audit;
+pub mod auth;
 pub mod handle_connection;
+pub mod http;
+pub mod lockout;
+pub mod metrics;
+pub mod quota;
+pub mod rate_limit;
+pub mod ticket;
+pub mod tls;
+pub mod traffic;
 
+use crate::{config::JwtConfig, connection::Offer, database, jwt, token};
+use lockout::Lockouts;
+use metrics::Metrics;
 use ptls::Ptls;
+use quota::QuotaTracker;
+use rand::Rng;
+use rate_limit::RateLimiter;
 use rsa::{pkcs1::DecodeRsaPrivateKey, RsaPrivateKey};
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use sqlx::sqlite::SqlitePool;
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+use ticket::Tickets;
+use tls::TlsConfigs;
 use tokio::{
+    fs::File,
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener, ToSocketAddrs,
     },
-    sync::Mutex,
+    sync::{broadcast, Mutex, Semaphore},
 };
+use tracing::{debug, field, info_span, warn, Instrument};
+use util::{Cmd, Protocol, SessionInfo};
+
+/// Connections that do not authenticate in time are dropped by default.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default cap of the connections that have not authenticated yet.
+const MAX_UNAUTHENTICATED: usize = 256;
+
+/// Admin commands are admitted this long after a TOTP step-up by default.
+const TOTP_GRACE: Duration = Duration::from_secs(300);
+
+/// A node that is not connected is asked this many times for a port, a
+/// retry apart, before the request fails.
+const SHARE_ATTEMPTS: u32 = 5;
+const SHARE_RETRY: Duration = Duration::from_secs(1);
+
+/// Forwarding streams are closed if their pair does not connect in time.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Server builer struct.
 #[derive(Default)]
 pub struct ServerBuilder {
     private_key: Option<RsaPrivateKey>,
     sqlite: Option<SqlitePool>,
+    audit_sink: Option<File>,
+    jwt: Option<jwt::Verifier>,
+    totp_grace: Option<Duration>,
+    auth_timeout: Option<Duration>,
+    max_unauthenticated: Option<usize>,
+    traffic_warnings: Vec<u8>,
+    terminate_over_quota: bool,
 }
 
 impl ServerBuilder {
@@ -39,30 +88,90 @@ impl ServerBuilder {
         self
     }
 
-    pub async fn sqlite_database(mut self, url: &str) -> Self {
-        let options = SqliteConnectOptions::from_str(url)
-            .unwrap()
-            .create_if_missing(true);
+    /// Appends the audit log also to `path` as JSON lines.
+    pub fn audit_file(mut self, path: &str) -> Self {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Cannot open audit file");
 
-        let database = SqlitePool::connect_with(options)
-            .await
-            .expect("Cannot connect sqlite database");
+        self.audit_sink = Some(File::from_std(file));
+        self
+    }
 
-        sqlx::migrate!("./migrations").run(&database).await.ok();
+    /// Accepts JWTs of the identity provider described by `config`.
+    pub fn jwt(mut self, config: &JwtConfig) -> Self {
+        self.jwt = Some(jwt::Verifier::new(config).expect("Cannot load JWT verification"));
+        self
+    }
+
+    /// How long a TOTP step-up admits admin commands, 5 minutes by default.
+    pub fn totp_grace(mut self, grace: Duration) -> Self {
+        self.totp_grace = Some(grace);
+        self
+    }
+
+    /// Time a connection has to authenticate, 10 seconds by default.
+    pub fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_timeout = Some(timeout);
+        self
+    }
+
+    /// Connections that have not authenticated yet beyond this many are
+    /// refused, 256 by default.
+    pub fn max_unauthenticated(mut self, max: usize) -> Self {
+        self.max_unauthenticated = Some(max);
+        self
+    }
+
+    /// Thresholds in percent of the transfer quotas that are warned about,
+    /// besides the quota being used up.
+    pub fn traffic_warnings(mut self, thresholds: Vec<u8>) -> Self {
+        self.traffic_warnings = thresholds;
+        self
+    }
+
+    /// Terminates the live sessions of clients that use up their transfer
+    /// quota, instead of only refusing new ones.
+    pub fn terminate_over_quota(mut self, terminate: bool) -> Self {
+        self.terminate_over_quota = terminate;
+        self
+    }
 
-        self.sqlite = Some(database);
+    pub async fn sqlite_database(mut self, url: &str) -> Self {
+        self.sqlite = Some(crate::database::connect(url).await);
         self
     }
 
     pub fn build(mut self) -> Arc<Server> {
+        let max_unauthenticated = self.max_unauthenticated.unwrap_or(MAX_UNAUTHENTICATED);
+        let private_key = self.private_key.take().expect("Private key is not given");
+
         Arc::new(Server {
-            private_key: self.private_key.take().expect("Private key is not given"),
+            salt_key: token::salt_key(&private_key),
+            private_key,
             sqlite: self
                 .sqlite
                 .take()
                 .expect("No sqlite database has been given"),
             forward_connections: Mutex::new(HashMap::new()),
             connections: Mutex::new(HashMap::new()),
+            metrics: Metrics::default(),
+            rate_limits: RateLimiter::default(),
+            quotas: QuotaTracker::default(),
+            audit_sink: self.audit_sink.take().map(Mutex::new),
+            jwt: self.jwt.take(),
+            totp_grace: self.totp_grace.unwrap_or(TOTP_GRACE),
+            lockouts: Lockouts::default(),
+            auth_timeout: self.auth_timeout.unwrap_or(AUTH_TIMEOUT),
+            unauthenticated: Arc::new(Semaphore::new(max_unauthenticated)),
+            max_unauthenticated,
+            tickets: Tickets::default(),
+            tls_configs: TlsConfigs::default(),
+            revocations: broadcast::channel(64).0,
+            traffic_warnings: self.traffic_warnings,
+            terminate_over_quota: self.terminate_over_quota,
         })
     }
 }
@@ -71,8 +180,30 @@ impl ServerBuilder {
 pub struct Server {
     private_key: RsaPrivateKey,
     sqlite: SqlitePool,
-    forward_connections: Mutex<HashMap<u64, (OwnedReadHalf, OwnedWriteHalf)>>,
+    forward_connections: Mutex<HashMap<u64, Offer>>,
     connections: Mutex<HashMap<String, Arc<Ptls<OwnedReadHalf, OwnedWriteHalf>>>>,
+    metrics: Metrics,
+    rate_limits: RateLimiter,
+    quotas: QuotaTracker,
+    audit_sink: Option<Mutex<File>>,
+    jwt: Option<jwt::Verifier>,
+    /// How long a TOTP step-up admits admin commands.
+    totp_grace: Duration,
+    /// Failed authentications by peer and identity.
+    lockouts: Lockouts,
+    auth_timeout: Duration,
+    /// Permits of the connections that have not authenticated yet.
+    unauthenticated: Arc<Semaphore>,
+    max_unauthenticated: usize,
+    /// Derives the salts of the challenges of unknown token ids, the same
+    /// across restarts.
+    salt_key: [u8; 32],
+    tickets: Tickets,
+    tls_configs: TlsConfigs,
+    /// Ids of revoked tokens, whose sessions are disconnected.
+    revocations: broadcast::Sender<String>,
+    traffic_warnings: Vec<u8>,
+    terminate_over_quota: bool,
 }
 
 impl Server {
@@ -81,8 +212,8 @@ impl Server {
         let listener = TcpListener::bind(addr).await.unwrap();
 
         loop {
-            let socket = match listener.accept().await {
-                Ok((socket, _)) => socket,
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
                 Err(_) => continue,
             };
 
@@ -90,8 +221,103 @@ impl Server {
                 continue;
             }
 
+            let Ok(permit) = Arc::clone(&self.unauthenticated).try_acquire_owned() else {
+                self.metrics
+                    .rejected_connections
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(%peer, "too many unauthenticated connections");
+                continue;
+            };
+
             let this = Arc::clone(&self);
-            tokio::spawn(this.handle_connection(socket));
+            tokio::spawn(
+                this.handle_connection(socket, peer, permit)
+                    .instrument(info_span!(
+                        "connection",
+                        %peer,
+                        hostname = field::Empty
+                    )),
+            );
+        }
+    }
+
+    /// Asks the node `hostname` to share its `port`. Returns id of the routing
+    /// request, or `None` if the node is not connected.
+    pub(crate) async fn request_share(
+        &self,
+        hostname: &str,
+        port: u32,
+        protocol: Protocol,
+    ) -> Option<u64> {
+        let id: u64 = rand::thread_rng().r#gen();
+
+        self.connections
+            .lock()
+            .await
+            .get(hostname)?
+            .send(&bincode::serialize(&Cmd::SharePort { port, id, protocol }).ok()?)
+            .await
+            .ok()?;
+
+        Some(id)
+    }
+
+    /// Offers a requester stream to the node answering the share request
+    /// `id`. The stream is closed if the node does not take it in time.
+    pub(crate) async fn offer(self: &Arc<Self>, id: u64, offer: Offer) {
+        self.forward_connections.lock().await.insert(id, offer);
+        let started_at = database::now();
+
+        let this = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(PAIRING_TIMEOUT).await;
+                let Some(offer) = this.forward_connections.lock().await.remove(&id) else {
+                    return;
+                };
+
+                this.metrics
+                    .pairing_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(session_id = id, "node did not pair in time");
+
+                let session = SessionInfo {
+                    id,
+                    requester: offer.requester,
+                    node: offer.node,
+                    port: offer.port,
+                    started_at,
+                    ended_at: database::now(),
+                    bytes_up: 0,
+                    bytes_down: 0,
+                    close_reason: String::from("pairing timeout"),
+                };
+                if let Err(error) = database::record_session(&this.sqlite, &session).await {
+                    warn!(%error, "cannot record session");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Takes the requester stream offered for the share request `id`, waiting
+    /// for it up to the pairing timeout.
+    pub(crate) async fn take_offer(&self, id: u64) -> Option<Offer> {
+        let deadline = Instant::now() + PAIRING_TIMEOUT;
+
+        loop {
+            if let Some(offer) = self.forward_connections.lock().await.remove(&id) {
+                return Some(offer);
+            }
+
+            if Instant::now() >= deadline {
+                self.metrics
+                    .pairing_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
     }
 }
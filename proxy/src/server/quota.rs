@@ -0,0 +1,212 @@
+use crate::database;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use util::{QuotaUsage, Quotas};
+
+/// Window of the requests per minute quota.
+const WINDOW: Duration = Duration::from_secs(60);
+
+type UsageMap = Arc<Mutex<HashMap<String, Usage>>>;
+
+/// Quota usage of the clients, requesters and nodes alike.
+#[derive(Default)]
+pub(crate) struct QuotaTracker {
+    usage: UsageMap,
+}
+
+#[derive(Default)]
+struct Usage {
+    forwards: u64,
+    /// Times of the requests in the last minute, oldest first.
+    requests: VecDeque<Instant>,
+}
+
+/// Admitted forward, counted against the quotas of its requester and node
+/// until dropped.
+pub struct ForwardPermit {
+    usage: UsageMap,
+    hostnames: Vec<String>,
+}
+
+impl Usage {
+    fn prune(&mut self, now: Instant) {
+        while self
+            .requests
+            .front()
+            .is_some_and(|requested| now.duration_since(*requested) >= WINDOW)
+        {
+            self.requests.pop_front();
+        }
+    }
+}
+
+impl QuotaTracker {
+    /// Admits a forward from `requester` to `node` if neither of them would
+    /// exceed its quotas. Returns the reason otherwise.
+    pub(crate) fn admit(
+        &self,
+        (requester, requester_quotas): (&str, Quotas),
+        (node, node_quotas): (&str, Quotas),
+    ) -> Result<ForwardPermit, String> {
+        self.admit_all(&[
+            ("client", requester, requester_quotas),
+            ("node", node, node_quotas),
+        ])
+    }
+
+    /// Admits an HTTP or TLS routed forward to `node`, whose requester is not
+    /// a client. Returns the reason otherwise.
+    pub(crate) fn admit_routed(
+        &self,
+        (node, quotas): (&str, Quotas),
+    ) -> Result<ForwardPermit, String> {
+        self.admit_all(&[("node", node, quotas)])
+    }
+
+    fn admit_all(&self, parties: &[(&str, &str, Quotas)]) -> Result<ForwardPermit, String> {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+
+        for (role, hostname, quotas) in parties {
+            let usage = usage.entry(hostname.to_string()).or_default();
+            usage.prune(now);
+
+            if let Some(max) = quotas.forwards.filter(|max| usage.forwards >= *max) {
+                return Err(format!("{role} {hostname} has {max} concurrent forwards"));
+            }
+            if let Some(max) = quotas
+                .requests_per_minute
+                .filter(|max| usage.requests.len() as u64 >= *max)
+            {
+                return Err(format!(
+                    "{role} {hostname} made {max} requests in the last minute"
+                ));
+            }
+        }
+
+        let mut hostnames: Vec<String> = Vec::new();
+        for (_, hostname, _) in parties {
+            if !hostnames.iter().any(|admitted| admitted == hostname) {
+                hostnames.push(hostname.to_string());
+            }
+        }
+        for hostname in &hostnames {
+            let usage = usage.get_mut(hostname).unwrap();
+            usage.forwards += 1;
+            usage.requests.push_back(now);
+        }
+
+        Ok(ForwardPermit {
+            usage: Arc::clone(&self.usage),
+            hostnames,
+        })
+    }
+
+    /// Current usage of the forward and request quotas of `hostname`.
+    pub(crate) fn usage(&self, hostname: &str) -> QuotaUsage {
+        let mut usage = self.usage.lock().unwrap();
+        let Some(usage) = usage.get_mut(hostname) else {
+            return QuotaUsage::default();
+        };
+        usage.prune(Instant::now());
+
+        QuotaUsage {
+            forwards: usage.forwards,
+            requests_per_minute: usage.requests.len() as u64,
+            ..QuotaUsage::default()
+        }
+    }
+}
+
+impl super::Server {
+    /// Admits a routed forward to `node` against its quotas. Returns the
+    /// reason otherwise.
+    pub(crate) async fn admit_routed(&self, node: &str) -> Result<ForwardPermit, String> {
+        let quotas = database::quotas(&self.sqlite, node)
+            .await
+            .map_err(|_| "cannot read quotas".to_string())?;
+
+        self.quotas.admit_routed((node, quotas.unwrap_or_default()))
+    }
+}
+
+impl Drop for ForwardPermit {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap();
+        let now = Instant::now();
+
+        for hostname in &self.hostnames {
+            if let Some(entry) = usage.get_mut(hostname) {
+                entry.forwards -= 1;
+                entry.prune(now);
+                if entry.forwards == 0 && entry.requests.is_empty() {
+                    usage.remove(hostname);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ForwardPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ForwardPermit")
+            .field(&self.hostnames)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(forwards: Option<u64>, requests_per_minute: Option<u64>) -> Quotas {
+        Quotas {
+            forwards,
+            requests_per_minute,
+            ..Quotas::default()
+        }
+    }
+
+    #[test]
+    fn concurrent_forwards_are_released_by_permits() {
+        let tracker = QuotaTracker::default();
+        let node = ("node", quotas(Some(1), None));
+
+        let permit = tracker.admit(("client", Quotas::default()), node).unwrap();
+        assert!(tracker.admit(("other", Quotas::default()), node).is_err());
+        assert!(tracker.admit_routed(node).is_err());
+
+        drop(permit);
+        assert!(tracker.admit_routed(node).is_ok());
+        assert_eq!(tracker.usage("client").forwards, 0);
+    }
+
+    #[test]
+    fn routed_forwards_count_against_the_node() {
+        let tracker = QuotaTracker::default();
+        let node = ("node", quotas(None, Some(2)));
+
+        let _permits = [
+            tracker.admit_routed(node).unwrap(),
+            tracker.admit_routed(node).unwrap(),
+        ];
+        assert_eq!(
+            tracker.admit_routed(node).unwrap_err(),
+            "node node made 2 requests in the last minute"
+        );
+        assert_eq!(tracker.usage("node").forwards, 2);
+    }
+
+    #[test]
+    fn own_forwards_are_counted_once() {
+        let tracker = QuotaTracker::default();
+        let node = ("node", quotas(Some(2), None));
+
+        let _permit = tracker.admit(node, node).unwrap();
+        assert_eq!(tracker.usage("node").forwards, 1);
+    }
+}
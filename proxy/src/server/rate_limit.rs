@@ -0,0 +1,264 @@
+use crate::database;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Sleep,
+};
+use util::RateLimits;
+
+/// Rate of a bucket in bytes per second, 0 is unlimited. Shared so that
+/// admins can change it while the bucket is in use.
+type Rate = Arc<AtomicU64>;
+
+/// Token bucket holding up to a second worth of bytes. A read may take more
+/// tokens than available, the debt is paid by waiting before the next read.
+pub(crate) struct Bucket {
+    rate: Rate,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Shared buckets of a client and the rates of its per-session buckets.
+struct ClientBuckets {
+    up: Arc<Bucket>,
+    down: Arc<Bucket>,
+    session_up: Rate,
+    session_down: Rate,
+}
+
+/// Buckets of the clients, loaded from the database when they are first
+/// needed.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    clients: Mutex<HashMap<String, Arc<ClientBuckets>>>,
+}
+
+/// Reader that is throttled by buckets.
+pub(crate) struct Limited<R> {
+    inner: R,
+    buckets: Vec<Arc<Bucket>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bucket {
+    fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                // full, clamped to the rate on the first take
+                tokens: f64::MAX,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `n` tokens and returns how long to wait until the bucket is out
+    /// of debt.
+    fn take(&self, n: usize) -> Duration {
+        self.take_at(n, Instant::now())
+    }
+
+    fn take_at(&self, n: usize, now: Instant) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+
+        let mut state = self.state.lock().unwrap();
+        let refill = now.duration_since(state.refilled).as_secs_f64() * rate;
+        state.tokens = (state.tokens + refill).min(rate) - n as f64;
+        state.refilled = now;
+
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+impl ClientBuckets {
+    fn new(limits: &RateLimits) -> Self {
+        let rate = |rate: Option<u64>| Arc::new(AtomicU64::new(rate.unwrap_or(0)));
+
+        Self {
+            up: Arc::new(Bucket::new(rate(limits.up))),
+            down: Arc::new(Bucket::new(rate(limits.down))),
+            session_up: rate(limits.session_up),
+            session_down: rate(limits.session_down),
+        }
+    }
+
+    fn set(&self, limits: &RateLimits) {
+        for (rate, limit) in [
+            (&self.up.rate, limits.up),
+            (&self.down.rate, limits.down),
+            (&self.session_up, limits.session_up),
+            (&self.session_down, limits.session_down),
+        ] {
+            rate.store(limit.unwrap_or(0), Ordering::Relaxed);
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Applies new limits to the buckets of a client, if they are loaded.
+    pub(crate) fn update(&self, hostname: &str, limits: &RateLimits) {
+        if let Some(buckets) = self.clients.lock().unwrap().get(hostname) {
+            buckets.set(limits);
+        }
+    }
+
+    /// Forgets the buckets of a removed client.
+    pub(crate) fn remove(&self, hostname: &str) {
+        self.clients.lock().unwrap().remove(hostname);
+    }
+}
+
+impl<R> Limited<R> {
+    pub(crate) fn new(inner: R, buckets: Vec<Arc<Bucket>>) -> Self {
+        Self {
+            inner,
+            buckets,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Limited<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - filled;
+
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.take(read))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl super::Server {
+    /// Buckets that throttle what `sender` sends to `receiver` in a new
+    /// session. Either may be a peer address without limits.
+    pub(crate) async fn buckets(&self, sender: &str, receiver: &str) -> Vec<Arc<Bucket>> {
+        let mut buckets = Vec::new();
+
+        if let Some(sender) = self.client_buckets(sender).await {
+            buckets.push(Arc::clone(&sender.up));
+            buckets.push(Arc::new(Bucket::new(Arc::clone(&sender.session_up))));
+        }
+
+        if let Some(receiver) = self.client_buckets(receiver).await {
+            buckets.push(Arc::clone(&receiver.down));
+            buckets.push(Arc::new(Bucket::new(Arc::clone(&receiver.session_down))));
+        }
+
+        buckets
+    }
+
+    async fn client_buckets(&self, hostname: &str) -> Option<Arc<ClientBuckets>> {
+        if let Some(buckets) = self.rate_limits.clients.lock().unwrap().get(hostname) {
+            return Some(Arc::clone(buckets));
+        }
+
+        let limits = database::rate_limits(&self.sqlite, hostname).await.ok()??;
+
+        let mut clients = self.rate_limits.clients.lock().unwrap();
+        Some(Arc::clone(
+            clients
+                .entry(hostname.to_string())
+                .or_insert_with(|| Arc::new(ClientBuckets::new(&limits))),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bucket of `rate` bytes per second, full at the returned instant.
+    fn bucket(rate: u64) -> (Bucket, Instant) {
+        let bucket = Bucket::new(Arc::new(AtomicU64::new(rate)));
+        let start = Instant::now();
+        bucket.take_at(0, start);
+
+        (bucket, start)
+    }
+
+    #[test]
+    fn unlimited_rates_never_wait() {
+        let (bucket, start) = bucket(0);
+
+        assert_eq!(bucket.take_at(usize::MAX, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(usize::MAX, start), Duration::ZERO);
+    }
+
+    #[test]
+    fn bursts_are_clamped_to_a_second() {
+        let (bucket, start) = bucket(1000);
+
+        assert_eq!(bucket.take_at(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+
+        // idle time does not save up more than a second worth of bytes
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take_at(1500, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn debt_is_paid_by_refill() {
+        let (bucket, start) = bucket(1000);
+        assert_eq!(bucket.take_at(1500, start), Duration::from_millis(500));
+
+        let paid = start + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(0, paid), Duration::ZERO);
+        assert_eq!(
+            bucket.take_at(1000, paid + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn rate_changes_apply_to_the_next_take() {
+        let (bucket, start) = bucket(1000);
+        bucket.rate.store(100, Ordering::Relaxed);
+
+        assert_eq!(bucket.take_at(200, start), Duration::from_secs(1));
+
+        bucket.rate.store(0, Ordering::Relaxed);
+        assert_eq!(bucket.take_at(1 << 30, start), Duration::ZERO);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::database;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use util::{PermissionLevel, TicketPurpose};
+
+/// Tickets are refused once they are older than this.
+const TICKET_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Length of the HMAC-SHA256 tag that follows the claims of a ticket.
+const TAG_LENGTH: usize = 32;
+
+/// Signed content of a ticket.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Claims {
+    pub hostname: String,
+    pub permission_level: PermissionLevel,
+    /// Token the issuing session authenticated with, the ticket is refused
+    /// once it is revoked.
+    pub token_id: Option<String>,
+    pub purpose: TicketPurpose,
+    /// The share request id of `SharePort` tickets, a fresh id otherwise.
+    /// Each session id is redeemed once.
+    pub session_id: u64,
+    /// Unix time in milliseconds.
+    pub expires_at: u64,
+}
+
+/// Issues and redeems the tickets of data connections. The key lives as long
+/// as the server process, so tickets do not survive restarts.
+pub(crate) struct Tickets {
+    key: [u8; 32],
+    /// Session ids of the redeemed tickets that have not expired yet, with
+    /// their expiry.
+    redeemed: Mutex<HashMap<u64, u64>>,
+}
+
+impl Default for Tickets {
+    fn default() -> Self {
+        Self {
+            key: rand::thread_rng().gen(),
+            redeemed: Mutex::default(),
+        }
+    }
+}
+
+impl Tickets {
+    /// Ticket admitting `purpose` for the client `hostname`.
+    pub fn issue(
+        &self,
+        hostname: &str,
+        permission_level: PermissionLevel,
+        token_id: Option<String>,
+        purpose: TicketPurpose,
+    ) -> Vec<u8> {
+        let session_id = match purpose {
+            TicketPurpose::SharePort { id, .. } => id,
+            TicketPurpose::GetPort { .. } => rand::thread_rng().gen(),
+        };
+        let claims = Claims {
+            hostname: hostname.to_string(),
+            permission_level,
+            token_id,
+            purpose,
+            session_id,
+            expires_at: database::now() + TICKET_LIFETIME.as_millis() as u64,
+        };
+
+        let mut ticket = bincode::serialize(&claims).unwrap();
+        let tag = self.mac(&ticket).finalize().into_bytes();
+        ticket.extend_from_slice(&tag);
+        ticket
+    }
+
+    /// Checks a ticket and uses it up.
+    pub fn redeem(&self, ticket: &[u8]) -> Result<Claims, &'static str> {
+        let Some(length) = ticket.len().checked_sub(TAG_LENGTH) else {
+            return Err("malformed ticket");
+        };
+        let (claims, tag) = ticket.split_at(length);
+        if self.mac(claims).verify_slice(tag).is_err() {
+            return Err("invalid ticket");
+        }
+
+        let claims: Claims = bincode::deserialize(claims).map_err(|_| "malformed ticket")?;
+        let now = database::now();
+        if claims.expires_at < now {
+            return Err("ticket expired");
+        }
+
+        let mut redeemed = self.redeemed.lock().unwrap();
+        redeemed.retain(|_, expires_at| *expires_at >= now);
+        if redeemed
+            .insert(claims.session_id, claims.expires_at)
+            .is_some()
+        {
+            return Err("ticket already redeemed");
+        }
+
+        Ok(claims)
+    }
+
+    fn mac(&self, claims: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(claims);
+        mac
+    }
+}
@@ -0,0 +1,402 @@
+use crate::connection::{Offer, Receiver};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    time::Instant,
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{debug, info, info_span, Instrument};
+use util::Protocol;
+
+/// Maximum size of a TLS record.
+const MAX_RECORD_SIZE: usize = 5 + (1 << 14);
+
+/// Connections are dropped unless their ClientHello arrives and, for
+/// terminated routes, their handshake completes in time.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configurations of the terminated routes by server name, so that their
+/// certificates are loaded once rather than for every connection. Adding a
+/// route again reloads its certificate.
+#[derive(Default)]
+pub(crate) struct TlsConfigs(Mutex<HashMap<String, CachedConfig>>);
+
+struct CachedConfig {
+    certificate: String,
+    private_key: String,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfigs {
+    /// Configuration of the route `server_name`, loaded from the PEM files
+    /// unless it is cached.
+    fn get(
+        &self,
+        server_name: &str,
+        certificate: &str,
+        private_key: &str,
+    ) -> Option<Arc<ServerConfig>> {
+        if let Some(cached) = self.0.lock().unwrap().get(server_name) {
+            if cached.certificate == certificate && cached.private_key == private_key {
+                return Some(Arc::clone(&cached.config));
+            }
+        }
+
+        let config = Arc::new(server_config(certificate, private_key)?);
+        self.0.lock().unwrap().insert(
+            server_name.to_string(),
+            CachedConfig {
+                certificate: certificate.to_string(),
+                private_key: private_key.to_string(),
+                config: Arc::clone(&config),
+            },
+        );
+        Some(config)
+    }
+
+    /// Drops the cached configuration of a changed or removed route.
+    pub(crate) fn forget(&self, server_name: &str) {
+        self.0.lock().unwrap().remove(server_name);
+    }
+}
+
+impl super::Server {
+    /// Serves TLS, routing each connection to a node by its SNI. The
+    /// connection is either passed through or terminated on the proxy.
+    pub async fn serve_tls<T: ToSocketAddrs>(self: Arc<Self>, addr: T) -> ! {
+        let listener = TcpListener::bind(addr).await.unwrap();
+
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => continue,
+            };
+
+            let this = Arc::clone(&self);
+            tokio::spawn(
+                this.handle_tls(socket, peer)
+                    .instrument(info_span!("tls", %peer)),
+            );
+        }
+    }
+
+    async fn handle_tls(self: Arc<Self>, tcp: TcpStream, peer: SocketAddr) -> Option<()> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let Ok(server_name) = tokio::time::timeout_at(deadline, peek_server_name(&tcp)).await
+        else {
+            debug!("ClientHello timed out");
+            return None;
+        };
+        let server_name = server_name?;
+
+        let route = sqlx::query!(
+            "SELECT hostname, port, certificate, private_key FROM tls_routes WHERE server_name = ?",
+            server_name
+        )
+        .fetch_optional(&self.sqlite)
+        .await
+        .ok()?;
+        let Some(route) = route else {
+            debug!(server_name, "no tls route");
+            return None;
+        };
+        let port = u32::try_from(route.port).ok()?;
+
+        if self.traffic_exhausted(&route.hostname).await {
+            debug!(
+                server_name,
+                hostname = route.hostname,
+                "node used up its transfer quota"
+            );
+            return None;
+        }
+        let permit = match self.admit_routed(&route.hostname).await {
+            Ok(permit) => permit,
+            Err(message) => {
+                debug!(
+                    server_name,
+                    hostname = route.hostname,
+                    message,
+                    "quota exceeded"
+                );
+                return None;
+            }
+        };
+
+        let receiver: Receiver = if let (Some(certificate), Some(private_key)) =
+            (route.certificate, route.private_key)
+        {
+            let config = self
+                .tls_configs
+                .get(&server_name, &certificate, &private_key)?;
+            let accept = TlsAcceptor::from(config).accept(tcp);
+            let Ok(tls) = tokio::time::timeout_at(deadline, accept).await else {
+                debug!(server_name, "handshake timed out");
+                return None;
+            };
+            let tls = tls.ok()?;
+
+            let (r, w) = tokio::io::split(tls);
+            (Box::new(r), Box::new(w))
+        } else {
+            // The ClientHello is only peeked, so the node completes the
+            // handshake itself.
+            let (r, w) = tcp.into_split();
+            (Box::new(r), Box::new(w))
+        };
+
+        let Some(id) = self
+            .request_share(&route.hostname, port, Protocol::Tcp)
+            .await
+        else {
+            debug!(
+                server_name,
+                hostname = route.hostname,
+                "node is not connected"
+            );
+            return None;
+        };
+        info!(
+            server_name,
+            hostname = route.hostname,
+            port,
+            session_id = id,
+            "tls connection routed"
+        );
+        let offer = Offer {
+            requester: peer.to_string(),
+            node: route.hostname,
+            port,
+            receiver,
+            permit: Some(permit),
+            token_id: None,
+        };
+        self.offer(id, offer).await;
+
+        Some(())
+    }
+}
+
+/// Loads certificate chain and private key from PEM files.
+pub(crate) fn server_config(certificate: &str, private_key: &str) -> Option<ServerConfig> {
+    let certificates = CertificateDer::pem_file_iter(certificate)
+        .ok()?
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let private_key = PrivateKeyDer::from_pem_file(private_key).ok()?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)
+        .ok()
+}
+
+/// Peeks the ClientHello without consuming it and returns its SNI.
+async fn peek_server_name(tcp: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; MAX_RECORD_SIZE];
+    let mut peeked = 0;
+
+    loop {
+        let n = tcp.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+
+        if n >= 5 {
+            // handshake record
+            if buf[0] != 0x16 {
+                return None;
+            }
+
+            let length = 5 + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if length > buf.len() {
+                return None;
+            }
+            if n >= length {
+                return parse_server_name(&buf[5..length]);
+            }
+        }
+
+        if n == buf.len() {
+            return None;
+        }
+
+        // peek returns immediately while the buffered record is incomplete.
+        if n == peeked {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        peeked = n;
+    }
+}
+
+/// Parses server_name extension of a ClientHello handshake message.
+fn parse_server_name(handshake: &[u8]) -> Option<String> {
+    let mut reader = Reader(handshake);
+
+    // ClientHello
+    if reader.take(1)? != [1] {
+        return None;
+    }
+    reader.take(3)?;
+    // version and random
+    reader.take(2 + 32)?;
+    // session id, cipher suites and compression methods
+    reader.take_u8_prefixed()?;
+    reader.take_u16_prefixed()?;
+    reader.take_u8_prefixed()?;
+
+    let mut extensions = Reader(reader.take_u16_prefixed()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.take(2)?;
+        let extension = extensions.take_u16_prefixed()?;
+
+        // server_name
+        if kind == [0, 0] {
+            let mut names = Reader(Reader(extension).take_u16_prefixed()?);
+            while !names.0.is_empty() {
+                let name_kind = names.take(1)?;
+                let name = names.take_u16_prefixed()?;
+
+                // host_name
+                if name_kind == [0] {
+                    return Some(std::str::from_utf8(name).ok()?.to_lowercase());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn take_u8_prefixed(&mut self) -> Option<&'a [u8]> {
+        let n = self.take(1)?[0] as usize;
+        self.take(n)
+    }
+
+    fn take_u16_prefixed(&mut self) -> Option<&'a [u8]> {
+        let n = self.take(2)?;
+        self.take(u16::from_be_bytes([n[0], n[1]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_prefixed(bytes: &[u8]) -> Vec<u8> {
+        let mut prefixed = (bytes.len() as u16).to_be_bytes().to_vec();
+        prefixed.extend_from_slice(bytes);
+        prefixed
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut extension = kind.to_be_bytes().to_vec();
+        extension.extend(u16_prefixed(data));
+        extension
+    }
+
+    fn server_name(name: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0];
+        entry.extend(u16_prefixed(name));
+        extension(0, &u16_prefixed(&entry))
+    }
+
+    /// ClientHello handshake message with `extensions`.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend([0; 32]);
+        body.push(0);
+        body.extend(u16_prefixed(&[0x13, 0x01]));
+        body.extend([1, 0]);
+        body.extend(u16_prefixed(extensions));
+
+        let mut hello = vec![1];
+        hello.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend(body);
+        hello
+    }
+
+    #[test]
+    fn server_name_is_lowercased() {
+        let hello = client_hello(&server_name(b"Example.COM"));
+
+        assert_eq!(parse_server_name(&hello), Some("example.com".to_string()));
+    }
+
+    #[test]
+    fn other_extensions_are_skipped() {
+        let mut extensions = extension(10, &[0, 2, 0, 29]);
+        extensions.extend(server_name(b"example.com"));
+
+        assert_eq!(
+            parse_server_name(&client_hello(&extensions)),
+            Some("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn hello_without_server_name_is_none() {
+        assert_eq!(parse_server_name(&client_hello(&[])), None);
+        assert_eq!(
+            parse_server_name(&client_hello(&extension(10, &[0, 2, 0, 29]))),
+            None
+        );
+    }
+
+    #[test]
+    fn truncated_hello_is_none() {
+        let hello = client_hello(&server_name(b"example.com"));
+
+        for end in 0..hello.len() {
+            assert_eq!(parse_server_name(&hello[..end]), None, "{end} bytes");
+        }
+    }
+
+    #[test]
+    fn malformed_extensions_are_none() {
+        // extension longer than the extensions
+        let mut overlong = server_name(b"example.com");
+        overlong[3] += 1;
+        assert_eq!(parse_server_name(&client_hello(&overlong)), None);
+
+        // name list longer than the extension
+        let mut overlong = server_name(b"example.com");
+        overlong[5] += 1;
+        assert_eq!(parse_server_name(&client_hello(&overlong)), None);
+
+        // name that is not UTF-8
+        assert_eq!(
+            parse_server_name(&client_hello(&server_name(b"\xffexample.com"))),
+            None
+        );
+
+        // not a ClientHello
+        let mut hello = client_hello(&server_name(b"example.com"));
+        hello[0] = 2;
+        assert_eq!(parse_server_name(&hello), None);
+    }
+}
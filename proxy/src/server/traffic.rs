@@ -0,0 +1,114 @@
+use crate::database::{self, Traffic};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tracing::warn;
+use util::AuditEntry;
+
+/// Interval in which the traffic of live sessions is charged.
+const CHARGE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bytes of a session, charged to the transfer quotas of its parties while
+/// it runs.
+#[derive(Default)]
+pub(crate) struct SessionTraffic {
+    /// Bytes from the requester to the node.
+    pub(crate) up: AtomicU64,
+    /// Bytes from the node to the requester.
+    pub(crate) down: AtomicU64,
+    charged: AtomicU64,
+}
+
+impl super::Server {
+    /// Charges the bytes of a session since the last charge to the
+    /// transfer quotas of `parties`. Parties that are not clients, such as
+    /// peers of routed connections, are not charged. Returns whether a quota
+    /// of them is used up.
+    pub(crate) async fn charge_traffic(
+        &self,
+        traffic: &SessionTraffic,
+        parties: [&str; 2],
+    ) -> bool {
+        let total = traffic.up.load(Ordering::Relaxed) + traffic.down.load(Ordering::Relaxed);
+        let bytes = total - traffic.charged.swap(total, Ordering::Relaxed);
+        if bytes == 0 {
+            return false;
+        }
+
+        let mut exhausted = false;
+        for hostname in parties {
+            match database::charge_traffic(&self.sqlite, hostname, bytes).await {
+                Ok(Some(usage)) => exhausted |= self.warn_traffic(hostname, usage).await,
+                Ok(None) => {}
+                Err(error) => warn!(%error, hostname, "cannot charge traffic"),
+            }
+        }
+
+        exhausted
+    }
+
+    /// Charges a live session every few seconds. Returns once a transfer
+    /// quota is used up if such sessions are terminated, never otherwise.
+    pub(crate) async fn meter_traffic(&self, traffic: &SessionTraffic, parties: [&str; 2]) {
+        loop {
+            tokio::time::sleep(CHARGE_INTERVAL).await;
+
+            if self.charge_traffic(traffic, parties).await && self.terminate_over_quota {
+                return;
+            }
+        }
+    }
+
+    /// Whether `hostname` has used up its transfer quota. Lookup errors do
+    /// not refuse forwards.
+    pub(crate) async fn traffic_exhausted(&self, hostname: &str) -> bool {
+        database::traffic_exhausted(&self.sqlite, hostname)
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Warns about the highest newly reached threshold of the transfer quota
+    /// of `hostname`. Returns whether the quota is used up.
+    async fn warn_traffic(&self, hostname: &str, usage: Traffic) -> bool {
+        let Some(quota) = usage.quota else {
+            return false;
+        };
+        let percent = (u128::from(usage.bytes) * 100 / u128::from(quota.max(1))) as u64;
+
+        let reached = self
+            .traffic_warnings
+            .iter()
+            .copied()
+            .chain([100])
+            .filter(|threshold| u64::from(*threshold) <= percent && *threshold > usage.warned)
+            .max();
+
+        if let Some(threshold) = reached {
+            if let Err(error) =
+                database::set_traffic_warned(&self.sqlite, hostname, threshold).await
+            {
+                warn!(%error, hostname, "cannot record traffic warning");
+            }
+
+            warn!(
+                hostname,
+                bytes = usage.bytes,
+                quota,
+                threshold,
+                "transfer quota threshold reached"
+            );
+            self.audit(AuditEntry {
+                timestamp: database::now(),
+                actor: None,
+                peer: String::from("-"),
+                action: String::from("transfer_quota"),
+                target: Some(hostname.to_string()),
+                outcome: format!("{threshold}% used"),
+            })
+            .await;
+        }
+
+        usage.bytes >= quota
+    }
+}
@@ -0,0 +1,106 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Parses CIDR ranges, a bare address being a range of its own, into the
+/// comma separated form stored with a client.
+pub fn normalize(sources: &[String]) -> Result<String, String> {
+    if sources.is_empty() {
+        return Err(String::from("no CIDR ranges given"));
+    }
+
+    let mut ranges: Vec<IpNet> = Vec::new();
+    for source in sources {
+        let source = source.trim();
+        let range = source
+            .parse::<IpNet>()
+            .or_else(|_| source.parse::<IpAddr>().map(IpNet::from))
+            .map_err(|_| format!("invalid CIDR range {source}"))?
+            .trunc();
+        if !ranges.contains(&range) {
+            ranges.push(range);
+        }
+    }
+
+    Ok(ranges
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// Ranges of the stored form.
+pub fn split(sources: &str) -> Vec<String> {
+    sources.split(',').map(String::from).collect()
+}
+
+/// Whether `ip` lies in one of the stored ranges. IPv4 peers of a dual-stack
+/// listener are matched as IPv4.
+pub fn allows(sources: &str, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+
+    sources
+        .split(',')
+        .filter_map(|range| range.parse::<IpNet>().ok())
+        .any(|range| range.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(sources: &[&str]) -> Result<String, String> {
+        normalize(
+            &sources
+                .iter()
+                .map(|source| source.to_string())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn normalizes_bare_addresses_to_single_ranges() {
+        assert_eq!(
+            normalized(&["192.0.2.7", "2001:db8::1"]).unwrap(),
+            "192.0.2.7/32,2001:db8::1/128"
+        );
+    }
+
+    #[test]
+    fn truncates_host_bits_and_drops_duplicates() {
+        assert_eq!(
+            normalized(&["10.1.2.3/8", " 10.0.0.0/8 ", "2001:db8::1/32"]).unwrap(),
+            "10.0.0.0/8,2001:db8::/32"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_and_empty_lists() {
+        assert!(normalized(&["10.0.0.0/33"]).is_err());
+        assert!(normalized(&["example.com"]).is_err());
+        assert!(normalized(&[]).is_err());
+    }
+
+    #[test]
+    fn allows_addresses_in_ranges() {
+        let sources = normalized(&["10.0.0.0/8", "192.0.2.7"]).unwrap();
+
+        assert!(allows(&sources, "10.200.0.1".parse().unwrap()));
+        assert!(allows(&sources, "192.0.2.7".parse().unwrap()));
+        assert!(!allows(&sources, "192.0.2.8".parse().unwrap()));
+        assert!(!allows(&sources, "2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_peers_as_ipv4() {
+        let sources = normalized(&["10.0.0.0/8"]).unwrap();
+
+        assert!(allows(&sources, "::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!allows(&sources, "::ffff:11.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn denies_unparsable_stored_ranges() {
+        assert!(!allows("not a range", "10.1.2.3".parse().unwrap()));
+        assert!(!allows("", "10.1.2.3".parse().unwrap()));
+    }
+}
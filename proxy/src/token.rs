@@ -0,0 +1,112 @@
+use crate::database;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, SaltString},
+    Argon2, Params,
+};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use rsa::{pkcs1::EncodeRsaPrivateKey, RsaPrivateKey};
+use sha2::{Digest, Sha256};
+use util::auth;
+
+/// Generates a token of the form `<id>:<secret>`. Returns the id and the
+/// token.
+pub fn generate() -> (String, String) {
+    let id = hex(&rand::thread_rng().gen::<[u8; 8]>());
+    let secret = hex(&rand::thread_rng().gen::<[u8; 32]>());
+
+    let token = format!("{id}{}{secret}", auth::SEPARATOR);
+    (id, token)
+}
+
+/// Generates a single-use enrollment code. Returns the code and its hash,
+/// only the hash is stored. The code is random enough for a plain SHA-256.
+pub fn enrollment_code() -> (String, String) {
+    let code = hex(&rand::thread_rng().gen::<[u8; 16]>());
+    let hash = code_hash(&code);
+
+    (code, hash)
+}
+
+/// Enrollment codes cannot be valid for longer than this many seconds.
+pub const MAX_ENROLLMENT_VALIDITY: u64 = 30 * 24 * 3600;
+
+/// Expiry of an enrollment code valid for `valid_for` seconds from now, unix
+/// time in milliseconds. Returns `None` for no or too long validity.
+pub fn enrollment_expiry(valid_for: u64) -> Option<u64> {
+    if valid_for == 0 || valid_for > MAX_ENROLLMENT_VALIDITY {
+        return None;
+    }
+
+    Some(database::now() + valid_for * 1000)
+}
+
+/// Hash an enrollment code is stored and looked up by.
+pub fn code_hash(code: &str) -> String {
+    hex(&Sha256::digest(code.as_bytes()))
+}
+
+/// Hashes a secret with Argon2 and a random salt, in the PHC string format.
+pub fn hash(secret: &str) -> String {
+    let salt = SaltString::encode_b64(&rand::thread_rng().gen::<[u8; 16]>()).unwrap();
+
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Splits an Argon2 hash into its setting, which is sent in challenges, and
+/// the stored key that verifies proofs. The raw hash is not kept, it would
+/// produce proofs.
+pub fn stored_key(hash: &str) -> Option<(String, Vec<u8>)> {
+    let salted_key = PasswordHash::new(hash).ok()?.hash?;
+    let (setting, _) = hash.rsplit_once('$')?;
+
+    Some((setting.to_string(), auth::stored_key(salted_key.as_bytes())))
+}
+
+/// Setting sent in challenges of unknown token ids. The salt is derived from
+/// the id with `key`, so that unknown ids get the same setting each time
+/// like known ones.
+pub fn dummy_setting(token_id: &str, key: &[u8]) -> String {
+    let salt = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC takes keys of any size")
+        .chain_update(token_id.as_bytes())
+        .finalize()
+        .into_bytes();
+    let salt = SaltString::encode_b64(&salt[..16]).unwrap();
+
+    format!(
+        "$argon2id$v=19$m={},t={},p={}${}",
+        Params::DEFAULT_M_COST,
+        Params::DEFAULT_T_COST,
+        Params::DEFAULT_P_COST,
+        salt.as_str()
+    )
+}
+
+/// Key of the salts of [`dummy_setting`], derived from the private key of the
+/// server. A random key would change the settings of unknown ids on restart
+/// while those of known ids stay, telling them apart.
+pub fn salt_key(private_key: &RsaPrivateKey) -> [u8; 32] {
+    let der = private_key
+        .to_pkcs1_der()
+        .expect("private keys can be encoded");
+
+    Sha256::new()
+        .chain_update(b"proxy dummy salts")
+        .chain_update(der.as_bytes())
+        .finalize()
+        .into()
+}
+
+/// Whether `hash` is an Argon2 hash rather than a plaintext token of an
+/// older database.
+pub fn is_hash(hash: &str) -> bool {
+    hash.starts_with("$argon2")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
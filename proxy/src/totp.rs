@@ -0,0 +1,132 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use util::auth;
+
+/// Milliseconds each code is valid for.
+const STEP: u64 = 30_000;
+
+/// Steps before and after the current one whose codes are accepted, for
+/// clocks that drift apart.
+const SKEW: u64 = 1;
+
+const DIGITS: u32 = 6;
+
+/// Generates a secret of the length of a SHA-1 output.
+pub fn generate_secret() -> Vec<u8> {
+    rand::thread_rng().gen::<[u8; 20]>().to_vec()
+}
+
+/// Unpadded base32 of `secret`, the form authenticator apps take.
+pub fn base32(secret: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut encoded = String::new();
+    for chunk in secret.chunks(5) {
+        let mut block = [0; 5];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let bits = block
+            .iter()
+            .fold(0, |bits, &byte| bits << 8 | u64::from(byte));
+
+        // 8 bits per byte in 5 bit characters, rounded up
+        for index in 0..(chunk.len() * 8).div_ceil(5) {
+            encoded.push(ALPHABET[(bits >> (35 - index * 5) & 31) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// `otpauth://` URI of `secret` for the client `hostname`.
+pub fn uri(secret: &[u8], hostname: &str) -> String {
+    format!(
+        "otpauth://totp/proxy:{hostname}?secret={}&issuer=proxy&algorithm=SHA1&digits={DIGITS}&period={}",
+        base32(secret),
+        STEP / 1000
+    )
+}
+
+/// Step of `code` if it is the code of a step around `now`, unix time in
+/// milliseconds.
+pub fn verify(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let current = now / STEP;
+
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|&step| auth::constant_time_eq(generate(secret, step).as_bytes(), code.as_bytes()))
+}
+
+/// Code of `secret` for the time step `step`, see RFC 6238.
+fn generate(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // dynamic truncation of RFC 4226
+    let offset = usize::from(hash[19] & 0xf);
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret of the SHA-1 test vectors of RFC 6238.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn generates_rfc_6238_codes() {
+        // the 8 digit codes of the RFC truncated to 6 digits
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(generate(SECRET, time * 1000 / STEP), code, "at {time}");
+        }
+    }
+
+    #[test]
+    fn encodes_rfc_4648_base32() {
+        for (input, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32(input.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn verifies_codes_within_skew() {
+        assert_eq!(verify(SECRET, "287082", 59_000), Some(1));
+        assert_eq!(verify(SECRET, "287082", 59_000 + STEP), Some(1));
+        assert_eq!(verify(SECRET, "287082", 59_000 - STEP), Some(1));
+        assert_eq!(verify(SECRET, "287082", 59_000 + 2 * STEP), None);
+    }
+
+    #[test]
+    fn rejects_wrong_codes() {
+        assert_eq!(verify(SECRET, "287083", 59_000), None);
+        assert_eq!(verify(SECRET, "28708", 59_000), None);
+        assert_eq!(verify(SECRET, "", 59_000), None);
+        assert_eq!(verify(b"another secret", "287082", 59_000), None);
+    }
+}
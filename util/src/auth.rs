@@ -0,0 +1,148 @@
+//! Challenge-response authentication with the client tokens.
+//!
+//! A token is `<id>:<secret>`. To authenticate, the client sends the token
+//! id and receives a fresh nonce with the Argon2 setting (algorithm,
+//! parameters and salt) of the token. Like in SCRAM, the client derives the
+//! salted key from the secret with the setting and from it the client key,
+//! while the server only stores the SHA-256 of the client key, the stored
+//! key. The proof is the client key masked with an HMAC of the nonce keyed
+//! with the stored key. The server unmasks it and compares its hash with the
+//! stored key, so neither the secret nor the client key cross the wire, and
+//! what the server stores does not suffice to authenticate.
+//!
+//! Clients with a registered RSA public key can authenticate without a
+//! token. They send the PKCS#1 PEM public key instead of a token id and
+//! prove the matching private key by signing the nonce.
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher},
+    Argon2, Params,
+};
+use hmac::{Hmac, Mac};
+use rsa::{
+    pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey, LineEnding},
+    Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+
+/// Separates the id from the secret in a token.
+pub const SEPARATOR: char = ':';
+
+/// Domain separation of the proofs.
+const CONTEXT: &[u8] = b"proxy authentication v2";
+
+/// Message of the client key HMAC.
+const CLIENT_KEY: &[u8] = b"client";
+
+/// Domain separation of the signatures.
+const KEY_CONTEXT: &[u8] = b"proxy key authentication v1";
+
+/// Splits a token into its id and secret.
+pub fn split_token(token: &str) -> Option<(&str, &str)> {
+    token.split_once(SEPARATOR)
+}
+
+/// Hashes `secret` with the Argon2 `setting`, a PHC string without the hash.
+/// Returns the raw hash, the salted key of the proofs.
+pub fn derive_key(secret: &str, setting: &str) -> Option<Vec<u8>> {
+    let setting = PasswordHash::new(setting).ok()?;
+    let params = Params::try_from(&setting).ok()?;
+
+    let hash = Argon2::default()
+        .hash_password_customized(
+            secret.as_bytes(),
+            Some(setting.algorithm),
+            setting.version,
+            params,
+            setting.salt?,
+        )
+        .ok()?;
+
+    Some(hash.hash?.as_bytes().to_vec())
+}
+
+/// Key stored by the server for the salted key of a token, the SHA-256 of
+/// its client key.
+pub fn stored_key(salted_key: &[u8]) -> Vec<u8> {
+    Sha256::digest(client_key(salted_key)).to_vec()
+}
+
+/// Proof of the salted key for the challenge `nonce` of the token
+/// `token_id`.
+pub fn proof(salted_key: &[u8], nonce: &[u8], token_id: &str) -> Vec<u8> {
+    let client_key = client_key(salted_key);
+    let signature = signature(&Sha256::digest(&client_key), nonce, token_id);
+
+    xor(&client_key, &signature)
+}
+
+/// Checks a proof against the stored key in constant time.
+pub fn verify(stored_key: &[u8], nonce: &[u8], token_id: &str, proof: &[u8]) -> bool {
+    let signature = signature(stored_key, nonce, token_id);
+    if proof.len() != signature.len() {
+        return false;
+    }
+
+    constant_time_eq(&Sha256::digest(xor(proof, &signature)), stored_key)
+}
+
+/// Compares two byte strings in time independent of where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn client_key(salted_key: &[u8]) -> Vec<u8> {
+    Hmac::<Sha256>::new_from_slice(salted_key)
+        .expect("HMAC takes keys of any size")
+        .chain_update(CLIENT_KEY)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+fn signature(stored_key: &[u8], nonce: &[u8], token_id: &str) -> Vec<u8> {
+    Hmac::<Sha256>::new_from_slice(stored_key)
+        .expect("HMAC takes keys of any size")
+        .chain_update(CONTEXT)
+        .chain_update(nonce)
+        .chain_update(token_id.as_bytes())
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+/// Parses a PKCS#1 PEM public key and encodes it again, so the same key is
+/// always stored and looked up as the same string.
+pub fn normalize_public_key(pem: &str) -> Option<String> {
+    RsaPublicKey::from_pkcs1_pem(pem.trim())
+        .ok()?
+        .to_pkcs1_pem(LineEnding::LF)
+        .ok()
+}
+
+/// SHA-256 of the PKCS#1 DER encoding of a PEM public key, in hex.
+pub fn fingerprint(pem: &str) -> Option<String> {
+    let der = RsaPublicKey::from_pkcs1_pem(pem.trim()).ok()?.to_pkcs1_der().ok()?;
+
+    Some(Sha256::digest(der.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Signature of the challenge `nonce` with the private key of a client.
+pub fn sign(private_key: &RsaPrivateKey, nonce: &[u8]) -> Option<Vec<u8>> {
+    private_key.sign(Pkcs1v15Sign::new::<Sha256>(), &signed_digest(nonce)).ok()
+}
+
+/// Checks the signature of the challenge `nonce` with a PEM public key.
+pub fn verify_signature(public_key: &str, nonce: &[u8], signature: &[u8]) -> bool {
+    RsaPublicKey::from_pkcs1_pem(public_key).is_ok_and(|public_key| {
+        public_key.verify(Pkcs1v15Sign::new::<Sha256>(), &signed_digest(nonce), signature).is_ok()
+    })
+}
+
+fn signed_digest(nonce: &[u8]) -> Vec<u8> {
+    Sha256::new().chain_update(KEY_CONTEXT).chain_update(nonce).finalize().to_vec()
+}
@@ -0,0 +1,242 @@
+//! Layered configuration shared by the binaries.
+//!
+//! Values are merged from defaults, a TOML file, environment variables and
+//! command line flags, later layers taking precedence. Problems are collected
+//! instead of failing at the first one, so all of them can be reported at once.
+
+use serde::de::DeserializeOwned;
+use std::{collections::HashSet, fmt, fs, path::PathBuf};
+use toml::{Table, Value};
+
+/// Where a configuration value comes from.
+#[derive(Debug, Clone)]
+pub enum Origin {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Flag,
+}
+
+/// A problem found while loading the configuration.
+#[derive(Debug)]
+pub struct Problem {
+    key: Option<String>,
+    origin: Option<Origin>,
+    message: String,
+}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug, Default)]
+pub struct Problems(Vec<Problem>);
+
+/// Merges configuration layers and reads typed values out of them.
+///
+/// Flags take precedence over the environment, the environment over the file
+/// and the file over defaults, regardless of the order the layers are added.
+#[derive(Default)]
+pub struct Loader {
+    layers: Vec<(Origin, Table)>,
+    used: HashSet<String>,
+    problems: Vec<Problem>,
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `value` as `key` with the lowest precedence.
+    pub fn fallback(self, key: &str, value: impl Into<Value>) -> Self {
+        self.layer(Origin::Default, key, value.into())
+    }
+
+    /// Adds the TOML file at `path`, if given. An unreadable or malformed
+    /// file is recorded as a problem.
+    pub fn file(mut self, path: Option<&str>) -> Self {
+        let Some(path) = path else {
+            return self;
+        };
+        let origin = Origin::File(PathBuf::from(path));
+
+        match fs::read_to_string(path) {
+            Ok(content) => match content.parse::<Table>() {
+                Ok(table) => self.layers.push((origin, table)),
+                Err(error) => self.push(None, Some(origin), error.message()),
+            },
+            Err(error) => self.push(None, Some(origin), format!("cannot read: {error}")),
+        }
+
+        self
+    }
+
+    /// Adds the environment variable `name` as `key`, if it is set.
+    pub fn env(self, key: &str, name: &str) -> Self {
+        match std::env::var(name) {
+            Ok(value) => self.layer(Origin::Env(name.to_string()), key, Value::String(value)),
+            Err(_) => self,
+        }
+    }
+
+    /// Adds a command line flag as `key`, if it is given.
+    pub fn flag(self, key: &str, value: Option<impl Into<Value>>) -> Self {
+        match value {
+            Some(value) => self.layer(Origin::Flag, key, value.into()),
+            None => self,
+        }
+    }
+
+    /// Reads `key` from the layer with the highest precedence that has it.
+    /// A value of the wrong type is recorded as a problem.
+    pub fn get<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        self.used.insert(key.to_string());
+
+        let (origin, table) = self.find(key)?;
+        let (origin, value) = (origin.clone(), table[key].clone());
+
+        let result = match (&origin, &value) {
+            // environment variables are strings, unless they parse as
+            // another TOML value
+            (Origin::Env(_), Value::String(raw)) => T::deserialize(value.clone()).or_else(|error| {
+                format!("value = {raw}").parse::<Table>()
+                    .ok()
+                    .and_then(|mut table| table.remove("value"))
+                    .and_then(|value| T::deserialize(value).ok())
+                    .ok_or(error)
+            }),
+            _ => T::deserialize(value),
+        };
+
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.push(Some(key), Some(origin), error.message());
+                None
+            }
+        }
+    }
+
+    /// Reads `key` like [`Loader::get`], recording a problem if it is missing.
+    pub fn require<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        let found = self.layers.iter().any(|(_, table)| table.contains_key(key));
+        if !found {
+            self.push(Some(key), None, "is required");
+        }
+
+        self.get(key)
+    }
+
+    /// Records a problem with the value of `key`, e.g. a failed validation.
+    pub fn problem(&mut self, key: &str, message: impl Into<String>) {
+        let origin = self.find(key).map(|(origin, _)| origin.clone());
+
+        self.push(Some(key), origin, message);
+    }
+
+    /// Returns the collected problems, including unknown keys of the file.
+    pub fn finish(mut self) -> Problems {
+        let mut unknown = Vec::new();
+        for (origin, table) in &self.layers {
+            if let Origin::File(_) = origin {
+                for key in table.keys().filter(|key| !self.used.contains(*key)) {
+                    unknown.push(Problem {
+                        key: Some(key.clone()),
+                        origin: Some(origin.clone()),
+                        message: String::from("unknown key"),
+                    });
+                }
+            }
+        }
+        self.problems.extend(unknown);
+
+        Problems(self.problems)
+    }
+
+    /// Layer with the highest precedence that has `key`.
+    fn find(&self, key: &str) -> Option<&(Origin, Table)> {
+        self.layers
+            .iter()
+            .filter(|(_, table)| table.contains_key(key))
+            .max_by_key(|(origin, _)| origin.rank())
+    }
+
+    fn layer(mut self, origin: Origin, key: &str, value: Value) -> Self {
+        let mut table = Table::new();
+        table.insert(key.to_string(), value);
+        self.layers.push((origin, table));
+
+        self
+    }
+
+    fn push(&mut self, key: Option<&str>, origin: Option<Origin>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            key: key.map(str::to_string),
+            origin,
+            message: message.into(),
+        });
+    }
+}
+
+/// Whether `address` has the form `host:port`.
+pub fn is_address(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+impl Problems {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Origin {
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Default => 0,
+            Self::File(_) => 1,
+            Self::Env(_) => 2,
+            Self::Flag => 3,
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Env(name) => write!(f, "environment variable {name}"),
+            Self::Flag => write!(f, "command line"),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(key) = &self.key {
+            write!(f, "{key}: ")?;
+        }
+        write!(f, "{}", self.message.trim_end())?;
+        if let Some(origin) = &self.origin {
+            write!(f, " (from {origin})")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{problem}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Problems {}
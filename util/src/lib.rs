@@ -1,53 +1,431 @@
 use serde::{Deserialize, Serialize};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-
-// loads environment variables to &'static str
-#[macro_export]
-macro_rules! env {
-    ($($name: ident),*) => {
-        lazy_static::lazy_static! {
-            $(
-                static ref $name: &'static str = Box::leak(
-                    std::env::var(stringify!($name))
-                        .expect(&format!("Cannot find environment variable {}", stringify!($name))[..])
-                        .into_boxed_str()
-                );
-            )*
-        }
-    };
-}
+use std::{fmt, io, ops::Deref, time::{Duration, Instant}};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod auth;
+pub mod config;
+pub mod logging;
 
 /// Command interface for clients/servers
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Cmd {
     Noop,
+    /// Starts authenticating with the token `token_id`, answered by
+    /// `Challenge`. See [`auth`].
+    Hello {
+        token_id: String,
+    },
+    /// Starts authenticating with a registered PKCS#1 PEM public key instead
+    /// of a token, answered by `Challenge`.
+    KeyHello {
+        public_key: String,
+    },
+    /// Answers the last `Challenge` with [`auth::proof`], or with
+    /// [`auth::sign`] after `KeyHello`.
     Authenticate {
-        token: Vec<u8>,
+        proof: Vec<u8>,
+    },
+    /// Authenticates with a JWT of the identity provider the server trusts,
+    /// answered by `Ok`.
+    Bearer {
+        jwt: Secret<String>,
+    },
+    /// Exchanges a code of `CreateEnrollment` for a new client, answered by
+    /// `Enrolled`. The client authenticates with `public_key` if it is given,
+    /// otherwise with a generated token.
+    Enroll {
+        code: Secret<String>,
+        public_key: Option<String>,
+    },
+    /// Authenticates a data connection with a ticket of `IssueTicket` instead
+    /// of a token or key, and runs the command the ticket admits. Answered
+    /// like that command.
+    Redeem {
+        ticket: Secret<Vec<u8>>,
+    },
+    /// Issues a short-lived ticket admitting `purpose` on one data
+    /// connection, answered by `Ticket`.
+    IssueTicket {
+        purpose: TicketPurpose,
     },
     GetPort {
         hostname: String,
         port: u32,
+        protocol: Protocol,
     },
     SharePort {
         port: u32,
         id: u64,
+        protocol: Protocol,
     },
     ListClients {
         after: String,
         limit: u64,
     },
+    /// Adds a client with a token generated by the server, which responds
+    /// with `Token`. A client added with a `public_key` authenticates with it
+    /// and gets no token, the response is `Ok`.
     AddClient {
         username: String,
-        token: String,
         permission_level: PermissionLevel,
+        public_key: Option<String>,
     },
     RemoveClient {
         username: String,
     },
+    /// Mints a single-use code that enrolls the client `hostname` with
+    /// `permission_level` for `valid_for` seconds, which the proxy caps.
+    /// Answered by `EnrollmentCode`.
+    CreateEnrollment {
+        hostname: String,
+        permission_level: PermissionLevel,
+        valid_for: u64,
+    },
+    /// Adds a token to a client, answered by `Token`. `expires_at` is unix
+    /// time in milliseconds.
+    AddToken {
+        username: String,
+        label: String,
+        expires_at: Option<u64>,
+    },
+    /// Lists the tokens of a client, answered by `Tokens`.
+    ListTokens {
+        username: String,
+    },
+    /// Deletes the token `id` and disconnects the sessions authenticated with
+    /// it.
+    RevokeToken {
+        id: String,
+    },
+    /// Replaces the token the session authenticated with by a new one with
+    /// the same label and lifetime, answered by `Token`. Other sessions of
+    /// the old token are disconnected.
+    RotateToken,
+    /// Proves a TOTP code of the client, which admits its admin commands for
+    /// the grace window of the server.
+    StepUp {
+        code: String,
+    },
+    /// Generates a TOTP secret for the client, answered by `TotpSecret`. It
+    /// replaces the current one once confirmed with `ConfirmTotp`.
+    EnrollTotp,
+    /// Activates the secret of `EnrollTotp` with a code it generated.
+    ConfirmTotp {
+        code: String,
+    },
+    /// Removes the TOTP secret of a client, e.g. after losing its device.
+    DisableTotp {
+        username: String,
+    },
+    /// Registers the PKCS#1 PEM public key of a client, replacing its former
+    /// one, or removes it if `public_key` is `None`.
+    SetPublicKey {
+        username: String,
+        public_key: Option<String>,
+    },
+    /// Restricts the addresses a client may authenticate from to CIDR ranges,
+    /// or lifts the restriction if `sources` is `None`. Live sessions are
+    /// kept.
+    SetAllowedSources {
+        username: String,
+        sources: Option<Vec<String>>,
+    },
+    /// Replaces the bandwidth limits of a client. Live sessions are throttled
+    /// with the new limits right away.
+    SetRateLimits {
+        username: String,
+        limits: RateLimits,
+    },
+    /// Replaces the forward and request quotas of a client.
+    SetQuotas {
+        username: String,
+        quotas: Quotas,
+    },
+    /// Routes HTTP requests with `Host: domain` to `port` of the node `hostname`.
+    AddHttpRoute {
+        domain: String,
+        hostname: String,
+        port: u32,
+    },
+    RemoveHttpRoute {
+        domain: String,
+    },
+    /// Routes TLS connections with SNI `server_name` to `port` of the node
+    /// `hostname`. The connection is passed through without decrypting unless
+    /// `termination` is given.
+    AddTlsRoute {
+        server_name: String,
+        hostname: String,
+        port: u32,
+        termination: Option<TlsTermination>,
+    },
+    RemoveTlsRoute {
+        server_name: String,
+    },
+    /// Response of a successful command.
+    Ok,
+    /// Response of a failed command.
+    Error {
+        message: String,
+    },
+    /// Response of `Hello` and `KeyHello`. `setting` is the Argon2 setting of
+    /// the token hash, empty after `KeyHello`, and `nonce` may only be
+    /// answered once.
+    Challenge {
+        nonce: Vec<u8>,
+        setting: String,
+    },
+    /// Response of a failed `Authenticate`.
+    AuthenticationFailed {
+        message: String,
+    },
+    /// Response of a command refused because a quota of the client or of the
+    /// requested node is used up.
+    QuotaExceeded {
+        message: String,
+    },
+    /// Lists the latest forwarding sessions, newest first. Only the sessions
+    /// `hostname` requested or served are listed if it is given.
+    ListSessions {
+        hostname: Option<String>,
+        limit: u64,
+    },
+    /// Lists the transfer quota usage of the latest periods, newest first.
+    /// Only the usage of `hostname` is listed if it is given.
+    ListTraffic {
+        hostname: Option<String>,
+        limit: u64,
+    },
+    /// Resets the transfer quota usage of a client in the current period.
+    ResetTraffic {
+        username: String,
+    },
+    /// Lists the latest audit log entries matching `filter`, newest first.
+    ListAudit {
+        filter: AuditFilter,
+        limit: u64,
+    },
+    /// Response of `AddClient`, `AddToken` and `RotateToken`. The server only
+    /// keeps a hash of the token, so it cannot be shown again.
+    Token {
+        token: Secret<String>,
+    },
+    /// Response of `CreateEnrollment`.
+    EnrollmentCode {
+        code: Secret<String>,
+    },
+    /// Response of `Enroll`, `token` is shown once and only if no public key
+    /// was registered.
+    Enrolled {
+        hostname: String,
+        token: Option<Secret<String>>,
+    },
+    /// Response of `EnrollTotp`, the base32 `secret` and the `otpauth://`
+    /// URI with it for authenticator apps.
+    TotpSecret {
+        secret: Secret<String>,
+        uri: Secret<String>,
+    },
+    /// Response of `IssueTicket`.
+    Ticket {
+        ticket: Secret<Vec<u8>>,
+    },
+    /// Response of `ListTokens`.
+    Tokens {
+        tokens: Vec<TokenInfo>,
+    },
+    /// Response of `ListClients`.
+    Clients {
+        clients: Vec<ClientInfo>,
+    },
+    /// Response of `ListSessions`.
+    Sessions {
+        sessions: Vec<SessionInfo>,
+    },
+    /// Response of `ListTraffic`.
+    Traffic {
+        usage: Vec<TrafficUsage>,
+    },
+    /// Response of `ListAudit`.
+    Audit {
+        entries: Vec<AuditEntry>,
+    },
+}
+
+/// The single command a ticket admits on a data connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TicketPurpose {
+    /// Receiving `port` of the node `hostname`.
+    GetPort {
+        hostname: String,
+        port: u32,
+        protocol: Protocol,
+    },
+    /// Answering the share request `id`.
+    SharePort { port: u32, id: u64, protocol: Protocol },
+}
+
+impl TicketPurpose {
+    /// Command run when the ticket is redeemed.
+    pub fn command(&self) -> Cmd {
+        match self.clone() {
+            Self::GetPort {
+                hostname,
+                port,
+                protocol,
+            } => Cmd::GetPort {
+                hostname,
+                port,
+                protocol,
+            },
+            Self::SharePort { port, id, protocol } => Cmd::SharePort { port, id, protocol },
+        }
+    }
+}
+
+/// A client listed by `ListClients`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientInfo {
+    pub hostname: String,
+    pub permission_level: PermissionLevel,
+    pub rate_limits: RateLimits,
+    pub quotas: Quotas,
+    pub usage: QuotaUsage,
+    /// [`auth::fingerprint`] of the registered public key.
+    pub key_fingerprint: Option<String>,
+    /// Whether admin commands of the client require a TOTP code.
+    pub totp: bool,
+    /// CIDR ranges the client may authenticate from, `None` is anywhere.
+    pub allowed_sources: Option<Vec<String>>,
+}
+
+/// Bandwidth limits of a client in bytes per second, `None` is unlimited.
+/// `up` limits what the client sends and `down` what it receives, whether it
+/// requests a port or shares one. The `session_` limits apply to each
+/// forwarding session on its own, the others to all sessions together.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub up: Option<u64>,
+    pub down: Option<u64>,
+    pub session_up: Option<u64>,
+    pub session_down: Option<u64>,
+}
+
+/// Quotas of a client, `None` is unlimited. They count the forwards the
+/// client requests as well as the ones it serves as a node.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quotas {
+    /// Concurrent forwards, from the request until the session is closed.
+    pub forwards: Option<u64>,
+    /// Forward requests in the last minute.
+    pub requests_per_minute: Option<u64>,
+    /// Bytes sent and received per calendar month (UTC).
+    pub transfer: Option<u64>,
+}
+
+/// Current usage of the [`Quotas`] of a client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub forwards: u64,
+    pub requests_per_minute: u64,
+    /// Bytes in the current month.
+    pub transfer: u64,
+}
+
+/// Transfer quota usage of a client in a period, listed by `ListTraffic`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrafficUsage {
+    pub hostname: String,
+    /// Calendar month as `YYYY-MM`.
+    pub period: String,
+    pub bytes: u64,
+    /// Current transfer quota of the client.
+    pub quota: Option<u64>,
+}
+
+/// A finished forwarding session listed by `ListSessions`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+    /// Id of the share request, as in the logs.
+    pub id: u64,
+    /// Hostname of the requesting client, or peer address of a routed HTTP
+    /// or TLS connection.
+    pub requester: String,
+    pub node: String,
+    pub port: u32,
+    /// Unix time in milliseconds.
+    pub started_at: u64,
+    /// Unix time in milliseconds.
+    pub ended_at: u64,
+    /// Bytes from the requester to the node.
+    pub bytes_up: u64,
+    /// Bytes from the node to the requester.
+    pub bytes_down: u64,
+    pub close_reason: String,
+}
+
+/// A token listed by `ListTokens`, without its secret.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenInfo {
+    pub id: String,
+    pub label: String,
+    /// Unix time in milliseconds, 0 for tokens older than the field.
+    pub created_at: u64,
+    /// Unix time in milliseconds, `None` for tokens that do not expire.
+    pub expires_at: Option<u64>,
+}
+
+/// An administrative or security event recorded by the server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    /// Hostname of the client, if it has authenticated.
+    pub actor: Option<String>,
+    pub peer: String,
+    pub action: String,
+    pub target: Option<String>,
+    /// `ok` or the error message.
+    pub outcome: String,
+}
+
+/// Filters of `ListAudit`. Unset fields match every entry.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub peer: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    /// Unix time in milliseconds, inclusive.
+    pub since: Option<u64>,
+    /// Unix time in milliseconds, exclusive.
+    pub until: Option<u64>,
+}
+
+/// PEM files on the proxy server used for terminating TLS.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TlsTermination {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+/// Value that is serialized as is but redacted in `Debug` output, so it does
+/// not end up in logs.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret<T>(pub T);
+
+/// Transport protocol of a forwarded port. Datagrams are carried as length
+/// prefixed frames through the forwarding stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
 }
 
 /// Permission level of the client
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum PermissionLevel {
     Admin(u32),
     Standart,
@@ -55,27 +433,121 @@ pub enum PermissionLevel {
     Any,
 }
 
+impl<T> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
 impl Cmd {
+    /// Creates an error response.
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            message: message.into(),
+        }
+    }
+
     /// Checks required minimum permission level for executing the command.
     pub fn minimum_permission_level(&self) -> PermissionLevel {
         match self {
             Self::Noop => PermissionLevel::Any,
+            Self::Hello { .. } => PermissionLevel::Any,
+            Self::KeyHello { .. } => PermissionLevel::Any,
+            Self::Enroll { .. } => PermissionLevel::Any,
+            Self::Redeem { .. } => PermissionLevel::Any,
+            Self::IssueTicket { purpose } => purpose.command().minimum_permission_level(),
             Self::Authenticate { .. } => PermissionLevel::Any,
+            Self::Bearer { .. } => PermissionLevel::Any,
             Self::GetPort { .. } => PermissionLevel::Standart,
             Self::SharePort { .. } => PermissionLevel::Node,
             Self::ListClients { .. } => PermissionLevel::Admin(0),
             Self::AddClient {
                 permission_level, ..
-            } => match permission_level {
-                PermissionLevel::Admin(admin_level) => PermissionLevel::Admin(admin_level + 1),
-                _ => PermissionLevel::Admin(0),
-            },
+            } => permission_level.required_to_manage(),
             Self::RemoveClient { .. } => PermissionLevel::Admin(0),
+            Self::SetPublicKey { .. } => PermissionLevel::Admin(0),
+            Self::CreateEnrollment {
+                permission_level, ..
+            } => permission_level.required_to_manage(),
+            Self::AddToken { .. } => PermissionLevel::Admin(0),
+            Self::ListTokens { .. } => PermissionLevel::Admin(0),
+            Self::RevokeToken { .. } => PermissionLevel::Admin(0),
+            Self::RotateToken => PermissionLevel::Any,
+            Self::StepUp { .. } => PermissionLevel::Any,
+            Self::EnrollTotp => PermissionLevel::Admin(0),
+            Self::ConfirmTotp { .. } => PermissionLevel::Admin(0),
+            Self::DisableTotp { .. } => PermissionLevel::Admin(0),
+            Self::SetAllowedSources { .. } => PermissionLevel::Admin(0),
+            Self::SetRateLimits { .. } => PermissionLevel::Admin(0),
+            Self::SetQuotas { .. } => PermissionLevel::Admin(0),
+            Self::AddHttpRoute { .. } => PermissionLevel::Admin(0),
+            Self::RemoveHttpRoute { .. } => PermissionLevel::Admin(0),
+            Self::AddTlsRoute { .. } => PermissionLevel::Admin(0),
+            Self::RemoveTlsRoute { .. } => PermissionLevel::Admin(0),
+            Self::ListSessions { .. } => PermissionLevel::Admin(0),
+            Self::ListTraffic { .. } => PermissionLevel::Admin(0),
+            Self::ResetTraffic { .. } => PermissionLevel::Admin(0),
+            Self::ListAudit { .. } => PermissionLevel::Admin(0),
+            Self::Ok
+            | Self::Error { .. }
+            | Self::Challenge { .. }
+            | Self::AuthenticationFailed { .. }
+            | Self::QuotaExceeded { .. }
+            | Self::Token { .. }
+            | Self::Ticket { .. }
+            | Self::TotpSecret { .. }
+            | Self::EnrollmentCode { .. }
+            | Self::Enrolled { .. }
+            | Self::Tokens { .. }
+            | Self::Clients { .. }
+            | Self::Sessions { .. }
+            | Self::Traffic { .. }
+            | Self::Audit { .. } => PermissionLevel::Any,
+        }
+    }
+}
+
+impl std::str::FromStr for PermissionLevel {
+    type Err = String;
+
+    /// Parses `standard`, `node` or `admin:<level>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("admin", level)) => level
+                .parse()
+                .map(Self::Admin)
+                .map_err(|_| format!("invalid admin level: {level}")),
+            None if s == "standard" => Ok(Self::Standart),
+            None if s == "node" => Ok(Self::Node),
+            _ => Err(format!("invalid permission level: {s}")),
         }
     }
 }
 
 impl PermissionLevel {
+    /// Level required to add a client of this level or to change its
+    /// credentials.
+    pub fn required_to_manage(&self) -> Self {
+        match self {
+            Self::Admin(admin_level) => Self::Admin(admin_level + 1),
+            _ => Self::Admin(0),
+        }
+    }
+
     /// Checks whether `self` is at least `other`'s level.
     pub fn at_least(&self, other: &Self) -> bool {
         if let Self::Admin(_) = self {
@@ -95,21 +567,72 @@ impl PermissionLevel {
 }
 
 
-pub async fn copy_bidirectional((mut r, mut w): (OwnedReadHalf, OwnedWriteHalf), (mut target_r, mut target_w): (OwnedReadHalf, OwnedWriteHalf)) {
-    tokio::join!{
+/// Bytes copied by [`copy_bidirectional`] and how the copying ended.
+#[derive(Debug)]
+pub struct Transfer {
+    /// Bytes copied from the first stream to the second.
+    pub forward: u64,
+    /// Bytes copied from the second stream to the first.
+    pub backward: u64,
+    pub duration: Duration,
+    /// First error of either direction.
+    pub error: Option<io::Error>,
+}
+
+/// Copies data in both directions until both of the streams are closed.
+pub async fn copy_bidirectional<R, W, TR, TW>((mut r, mut w): (R, W), (mut target_r, mut target_w): (TR, TW)) -> Transfer
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    TR: AsyncRead + Unpin,
+    TW: AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+
+    let ((backward, backward_result), (forward, forward_result)) = tokio::join!{
         async move {
-            loop {
-                if tokio::io::copy(&mut target_r, &mut w).await.is_err() {
-                    break;
-                }
-            }
+            let copied = copy(&mut target_r, &mut w).await;
+            w.shutdown().await.ok();
+            copied
         },
         async move {
-            loop {
-                if tokio::io::copy(&mut r, &mut target_w).await.is_err() {
-                    break;
-                }
-            }
+            let copied = copy(&mut r, &mut target_w).await;
+            target_w.shutdown().await.ok();
+            copied
         }
     };
+
+    Transfer {
+        forward,
+        backward,
+        duration: started.elapsed(),
+        error: forward_result.err().or(backward_result.err()),
+    }
+}
+
+/// Copies until the reader is closed. Returns the copied bytes even if an
+/// error stops the copying.
+async fn copy<R, W>(r: &mut R, w: &mut W) -> (u64, io::Result<()>)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 8192];
+    let mut copied = 0;
+
+    loop {
+        let n = match r.read(&mut buf).await {
+            Ok(0) => return (copied, Ok(())),
+            Ok(n) => n,
+            Err(error) => return (copied, Err(error)),
+        };
+
+        if let Err(error) = async {
+            w.write_all(&buf[..n]).await?;
+            w.flush().await
+        }.await {
+            return (copied, Err(error));
+        }
+        copied += n as u64;
+    }
 }
@@ -0,0 +1,62 @@
+//! Logging setup shared by the binaries.
+
+use crate::config::Loader;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+/// Output format of the log lines.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Human,
+    Json,
+}
+
+/// Log level filter and format, read from the `log_level` and `log_format`
+/// configuration keys.
+pub struct Logging {
+    filter: EnvFilter,
+    format: Format,
+}
+
+impl Logging {
+    /// Adds the defaults and environment variables of the logging keys.
+    pub fn layers(loader: Loader) -> Loader {
+        loader
+            .fallback("log_level", "info")
+            .fallback("log_format", "human")
+            .env("log_level", "LOG_LEVEL")
+            .env("log_format", "LOG_FORMAT")
+    }
+
+    pub fn load(loader: &mut Loader) -> Option<Self> {
+        let level = loader.require::<String>("log_level");
+        let format = loader.require::<Format>("log_format");
+
+        let filter = match EnvFilter::try_new(level?) {
+            Ok(filter) => filter,
+            Err(error) => {
+                loader.problem("log_level", error.to_string());
+                return None;
+            }
+        };
+
+        Some(Self {
+            filter,
+            format: format?,
+        })
+    }
+
+    /// Installs the global subscriber writing to stderr.
+    pub fn init(self) {
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(self.filter)
+            .with_writer(std::io::stderr);
+
+        match self.format {
+            Format::Human => subscriber.init(),
+            Format::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+        }
+    }
+}